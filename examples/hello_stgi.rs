@@ -1,16 +1,26 @@
 // MINIMAL WGPU AND WINIT USAGE EXAMPLE + STGI
 // Most code is taken from https://sotrh.github.io/learn-wgpu and the winit documentation.
-use std::{num::NonZeroU32, sync::Arc, time::Instant};
+use std::{
+    num::NonZeroU32,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use pollster::FutureExt;
-use stgi::{builder::StgiBuilder, Stgi, Text, UiArea, UiAreaHandle, ZOrder};
+use stgi::{
+    builder::StgiBuilder,
+    input::MouseButton,
+    layout::{Anchor, Placement, Unit},
+    sprite::Playback,
+    StateSprites, Stgi, Text, UiArea, UiAreaHandle, ZOrder,
+};
 use wgpu::{
     Adapter, Device, Instance, InstanceDescriptor, MemoryHints, Queue, Surface,
     SurfaceConfiguration, SurfaceTargetUnsafe,
 };
 use winit::{
     application::ApplicationHandler,
-    event::{DeviceEvent, DeviceId, StartCause, WindowEvent},
+    event::{DeviceEvent, DeviceId, ElementState, MouseScrollDelta, StartCause, WindowEvent},
     event_loop::{ActiveEventLoop, ControlFlow, EventLoop},
     keyboard::Key,
     window::{Window, WindowAttributes, WindowId},
@@ -21,12 +31,8 @@ enum SpriteId {
     Logo,
     Title,
     TitleBackground,
-    TitleHovered,
     SpawnSmiley,
     SpawnSmileyHovered,
-    Smiley1,
-    Smiley2,
-    Smiley3,
     Blocky,
     LoadingSpinner,
 }
@@ -39,9 +45,10 @@ enum FontId {
 struct State {
     // STGI
     last_animation_tick: Instant,
+    // Timestamps of the input are measured from here
+    start: Instant,
     stgi: Stgi<SpriteId, FontId>,
     handle_title_background: UiAreaHandle,
-    handle_spinner: UiAreaHandle,
 
     // WGPU
     _instance: Instance,
@@ -121,26 +128,44 @@ impl State {
                 .unwrap()
                 .to_rgba8(),
         );
-        stgi.add_animated_sprite(
+        stgi.add_inanimate_sprite(
+            SpriteId::SpawnSmiley,
+            image::load_from_memory(include_bytes!("spawn_smiley.png"))
+                .unwrap()
+                .to_rgba8(),
+        );
+        stgi.add_inanimate_sprite(
+            SpriteId::SpawnSmileyHovered,
+            image::load_from_memory(include_bytes!("spawn_smiley_hovered.png"))
+                .unwrap()
+                .to_rgba8(),
+        );
+        stgi.add_animated_sprite_with(
             SpriteId::Blocky,
             image::load_from_memory(include_bytes!("blocky.png"))
                 .unwrap()
                 .to_rgba8(),
             None,
+            Duration::from_millis(50),
+            Playback::Loop,
         );
-        stgi.add_animated_sprite(
+        stgi.add_animated_sprite_with(
             SpriteId::LoadingSpinner,
             image::load_from_memory(include_bytes!("loading_spinner.png"))
                 .unwrap()
                 .to_rgba8(),
             None,
+            Duration::from_millis(50),
+            Playback::Loop,
         );
-        stgi.add_animated_sprite(
+        stgi.add_animated_sprite_with(
             SpriteId::TitleBackground,
             image::load_from_memory(include_bytes!("title_background.png"))
                 .unwrap()
                 .to_rgba8(),
             Some(NonZeroU32::new(128).unwrap()),
+            Duration::from_millis(50),
+            Playback::Loop,
         );
 
         let mut stgi = stgi.build(
@@ -151,48 +176,68 @@ impl State {
             surface_format,
             8192 * 8192,
         );
-        let window_width = size.width as f32;
-        let window_height = size.height as f32;
         stgi.add_area(UiArea {
             x_min: 20.0,
             x_max: 20.0 + 127.0,
             y_min: 20.0,
             y_max: 20.0 + 44.0,
-            z: ZOrder::Second,
+            z: ZOrder::new(1, 0),
             sprite: Some(SpriteId::Logo),
             enabled: true,
             text: None,
+            ..Default::default()
         });
         let handle_title_background = stgi.add_area(UiArea {
-            x_min: (window_width - 128.0 * 4.0) / 2.0,
-            x_max: (window_width + 128.0 * 4.0) / 2.0,
-            y_min: 100.0,
-            y_max: 100.0 + 14.0 * 4.0,
-            z: ZOrder::Second,
+            placement: Some(Placement {
+                anchor: Anchor::Top,
+                offset_x: Unit::Px(0.0),
+                offset_y: Unit::Px(100.0),
+                width: Unit::Px(128.0 * 4.0),
+                height: Unit::Px(14.0 * 4.0),
+            }),
+            z: ZOrder::new(1, 0),
             sprite: None,
             enabled: true,
-            text: Some(Text {
-                font: FontId::Default,
-                size: 64,
-                text: "STGI EXAMPLE".to_string(),
+            text: Some(Text::new(FontId::Default, 64, "STGI EXAMPLE")),
+            ..Default::default()
+        });
+        // Switches to the hovered sprite on its own
+        stgi.add_area(UiArea {
+            placement: Some(Placement {
+                anchor: Anchor::Bottom,
+                offset_x: Unit::Px(0.0),
+                offset_y: Unit::Px(-40.0),
+                width: Unit::Px(487.0),
+                height: Unit::Px(55.0),
             }),
+            z: ZOrder::new(1, 0),
+            sprite: Some(SpriteId::SpawnSmiley),
+            states: StateSprites {
+                hovered: Some(SpriteId::SpawnSmileyHovered),
+                ..Default::default()
+            },
+            ..Default::default()
         });
-        let handle_spinner = stgi.add_area(UiArea {
-            x_min: window_width - 20.0 - 16.0 * 4.0,
-            x_max: window_width - 20.0,
-            y_min: 20.0,
-            y_max: 20.0 + 16.0 * 4.0,
-            z: ZOrder::First,
+        stgi.add_area(UiArea {
+            placement: Some(Placement {
+                anchor: Anchor::TopRight,
+                offset_x: Unit::Px(-20.0),
+                offset_y: Unit::Px(20.0),
+                width: Unit::Px(16.0 * 4.0),
+                height: Unit::Px(16.0 * 4.0),
+            }),
+            z: ZOrder::new(0, 0),
             sprite: Some(SpriteId::LoadingSpinner),
             enabled: true,
             text: None,
+            ..Default::default()
         });
 
         Self {
             last_animation_tick: Instant::now(),
+            start: Instant::now(),
             stgi,
             handle_title_background,
-            handle_spinner,
             _instance: instance,
             surface,
             _adapter: adapter,
@@ -208,10 +253,10 @@ impl State {
     /// For the sake of simplicity we just hardcode a bunch of stuff.
     fn update_ui(&mut self) {
         self.stgi.update(&self.device, &self.queue);
-        if self.last_animation_tick.elapsed().as_millis() > 50 {
-            self.last_animation_tick = Instant::now();
-            self.stgi.next_animation_frame(&self.queue);
-        }
+        let now = Instant::now();
+        self.stgi
+            .advance_animations(&self.queue, now - self.last_animation_tick);
+        self.last_animation_tick = now;
     }
 
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -225,13 +270,6 @@ impl State {
                 new_size.width as f32,
                 new_size.height as f32,
             );
-            let area = self.stgi.area_mut(self.handle_title_background).unwrap();
-            area.x_min = (new_size.width as f32 - 128.0 * 4.0) / 2.0;
-            area.x_max = (new_size.width as f32 + 128.0 * 4.0) / 2.0;
-
-            let area = self.stgi.area_mut(self.handle_spinner).unwrap();
-            area.x_min = new_size.width as f32 - 20.0 - 16.0 * 4.0;
-            area.x_max = new_size.width as f32 - 20.0;
         }
     }
 
@@ -276,7 +314,9 @@ impl State {
         output.present();
         self.stgi.post_render_work();
         //self.stgi.post_render_work();
-        println!("Hovered: {:?}", self.stgi.currently_hovered_area());
+        while let Some(event) = self.stgi.poll_event() {
+            println!("Event: {:?}", event);
+        }
         Ok(())
     }
 }
@@ -307,22 +347,42 @@ impl ApplicationHandler for State {
             }
             // update cursor position
             WindowEvent::CursorMoved { position, .. } => {
-                self.stgi
-                    .set_cursor_pos(position.x as u32, position.y as u32);
+                self.stgi.set_cursor_pos(
+                    position.x as u32,
+                    position.y as u32,
+                    self.start.elapsed(),
+                );
             }
-            WindowEvent::MouseInput { state, button, .. } => match state {
-                winit::event::ElementState::Pressed => {
-                    if button == winit::event::MouseButton::Left {}
-                }
-                _ => {}
-            },
-            WindowEvent::KeyboardInput { event, .. } => match event.logical_key.as_ref() {
-                Key::Character("o") => {
+            WindowEvent::MouseInput { state, button, .. } => {
+                let button = match button {
+                    winit::event::MouseButton::Left => MouseButton::Left,
+                    winit::event::MouseButton::Right => MouseButton::Right,
+                    winit::event::MouseButton::Middle => MouseButton::Middle,
+                    winit::event::MouseButton::Back => MouseButton::Other(3),
+                    winit::event::MouseButton::Forward => MouseButton::Other(4),
+                    winit::event::MouseButton::Other(other) => MouseButton::Other(other),
+                };
+                self.stgi.set_mouse_button(
+                    button,
+                    state == ElementState::Pressed,
+                    self.start.elapsed(),
+                );
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let (delta_x, delta_y) = match delta {
+                    MouseScrollDelta::LineDelta(x, y) => (x, y),
+                    MouseScrollDelta::PixelDelta(position) => {
+                        (position.x as f32, position.y as f32)
+                    }
+                };
+                self.stgi.scroll_wheel(delta_x, delta_y);
+            }
+            WindowEvent::KeyboardInput { event, .. } => {
+                if let Key::Character("o") = event.logical_key.as_ref() {
                     let area = self.stgi.area_mut(self.handle_title_background).unwrap();
                     area.enabled = !area.enabled;
                 }
-                _ => {}
-            },
+            }
             _ => {}
         }
     }
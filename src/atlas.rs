@@ -0,0 +1,551 @@
+// The sprite atlas is a 2d array texture, every layer has its own allocator.
+// Sprites can be added and removed at any time: new frames are packed into free space and if there is none,
+// all layers grow (up to the max texture size) or a new layer is added. The texture, the offset and allocation
+// tables and the bind group are then recreated in flush().
+
+use ahash::HashMap;
+use bytemuck::Zeroable;
+use guillotiere::{size2, AllocId, AtlasAllocator, Rectangle};
+use image::{ImageBuffer, Rgba};
+use util::{BufferInitDescriptor, DeviceExt};
+use wgpu::*;
+
+use super::{sprite::Sprite, Allocation, SpriteId, SpriteInfo};
+
+type Frame = ImageBuffer<Rgba<u8>, Vec<u8>>;
+
+const INITIAL_SIZE: u32 = 128;
+
+struct AtlasSprite {
+    index: u32,
+    info: SpriteInfo,
+    // (atlas_index, allocation) per frame
+    allocations: Vec<(u32, AllocId, Rectangle)>,
+}
+
+/// Where the frames of the sprites are packed, the part of the atlas that needs no GPU.
+/// The software renderer packs its sprites with it too.
+pub(crate) struct AtlasPacker<S: SpriteId> {
+    sprites: HashMap<S, AtlasSprite>,
+    free_indices: Vec<u32>,
+    next_index: u32,
+
+    allocators: Vec<AtlasAllocator>,
+    size: u32,
+    max_size: u32,
+    max_layers: u32,
+    // (atlas_index, rect, frame) waiting to be written into the texture
+    pending_uploads: Vec<(u32, Rectangle, Frame)>,
+}
+
+impl<S: SpriteId> AtlasPacker<S> {
+    /// Grows up to the texture size and layer limits
+    pub fn with_limits(limits: &Limits) -> Self {
+        Self::new(
+            INITIAL_SIZE,
+            limits.max_texture_dimension_2d.min(65536),
+            limits.max_texture_array_layers,
+        )
+    }
+
+    fn new(size: u32, max_size: u32, max_layers: u32) -> Self {
+        Self {
+            sprites: HashMap::default(),
+            free_indices: Vec::new(),
+            next_index: 0,
+            allocators: Vec::new(),
+            size,
+            max_size,
+            max_layers,
+            pending_uploads: Vec::new(),
+        }
+    }
+
+    /// Width and height of every layer
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn layers(&self) -> u32 {
+        self.allocators.len() as u32
+    }
+
+    /// (atlas_index, x, y) of the top left corner of every frame of the sprite
+    pub fn positions(&self, sprite_id: &S) -> Option<Vec<(u32, u32, u32)>> {
+        let sprite = self.sprites.get(sprite_id)?;
+        let positions = sprite
+            .allocations
+            .iter()
+            .map(|(atlas_index, _, rect)| (*atlas_index, rect.min.x as u32, rect.min.y as u32));
+        Some(positions.collect())
+    }
+
+    /// The frames that were packed since the last call, with the layer and the rect they were packed into
+    pub fn take_uploads(&mut self) -> Vec<(u32, Rectangle, Frame)> {
+        std::mem::take(&mut self.pending_uploads)
+    }
+
+    /// Packs all frames of the sprite, a sprite with the same id must be removed first
+    pub fn insert(&mut self, sprite_id: S, sprite: &Sprite) {
+        let frames = sprite.frames();
+        let mut allocations = Vec::with_capacity(frames.len());
+        for frame in frames {
+            let (atlas_index, allocation) = self.allocate(frame.width(), frame.height());
+            self.pending_uploads
+                .push((atlas_index, allocation.rectangle, frame));
+            allocations.push((atlas_index, allocation.id, allocation.rectangle));
+        }
+        let index = self.free_indices.pop().unwrap_or_else(|| {
+            self.next_index += 1;
+            self.next_index - 1
+        });
+        let info = sprite.info(allocations.len() as u32);
+        self.sprites.insert(
+            sprite_id,
+            AtlasSprite {
+                index,
+                info,
+                allocations,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, sprite_id: &S) -> bool {
+        let Some(sprite) = self.sprites.remove(sprite_id) else {
+            return false;
+        };
+        for (atlas_index, id, rect) in sprite.allocations {
+            self.allocators[atlas_index as usize].deallocate(id);
+            self.pending_uploads
+                .retain(|(index, pending, _)| *index != atlas_index || *pending != rect);
+        }
+        self.free_indices.push(sprite.index);
+        true
+    }
+
+    fn allocate(&mut self, width: u32, height: u32) -> (u32, guillotiere::Allocation) {
+        let needed = width.max(height);
+        if needed > self.size {
+            let new_size = needed.next_power_of_two().min(self.max_size);
+            if new_size < needed {
+                panic!("Sprite too large to fit into a texture");
+            }
+            self.grow(new_size);
+        }
+        if self.allocators.is_empty() {
+            self.allocators.push(AtlasAllocator::new(size2(
+                self.size as i32,
+                self.size as i32,
+            )));
+        }
+        loop {
+            // Try to pack the sprite into one of the existing layers
+            for (index, allocator) in self.allocators.iter_mut().enumerate() {
+                if let Some(allocation) = allocator.allocate(size2(width as i32, height as i32)) {
+                    return (index as u32, allocation);
+                }
+            }
+            // Try to grow the layers, otherwise add a new one
+            let new_size = (self.size * 2).min(self.max_size);
+            if new_size > self.size {
+                self.grow(new_size);
+            } else if (self.allocators.len() as u32) < self.max_layers {
+                self.allocators.push(AtlasAllocator::new(size2(
+                    self.size as i32,
+                    self.size as i32,
+                )));
+            } else {
+                panic!("Sprite atlas overflow, no texture space left");
+            }
+        }
+    }
+
+    fn grow(&mut self, new_size: u32) {
+        for allocator in &mut self.allocators {
+            allocator.grow(size2(new_size as i32, new_size as i32));
+        }
+        self.size = new_size;
+    }
+}
+
+pub(crate) struct SpriteAtlas<S: SpriteId> {
+    packer: AtlasPacker<S>,
+
+    texture: Texture,
+    texture_size: u32,
+    texture_layers: u32,
+    sampler: Sampler,
+    bind_group_layout: BindGroupLayout,
+    bind_group: BindGroup,
+}
+
+impl<S: SpriteId> SpriteAtlas<S> {
+    pub fn new(device: &Device) -> Self {
+        let limits = device.limits();
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("STGI Atlas Sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Nearest,
+            min_filter: FilterMode::Nearest,
+            mipmap_filter: FilterMode::Nearest,
+            ..Default::default()
+        });
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::VERTEX,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: TextureViewDimension::D2Array,
+                        sample_type: TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("Stgi atlas bind group layout"),
+        });
+        let texture = Self::create_texture(device, INITIAL_SIZE, 1);
+        let bind_group = Self::create_bind_group(
+            device,
+            &bind_group_layout,
+            &texture,
+            &sampler,
+            &[SpriteInfo::zeroed()],
+            &[Allocation::zeroed()],
+        );
+        Self {
+            packer: AtlasPacker::with_limits(&limits),
+            texture,
+            texture_size: INITIAL_SIZE,
+            texture_layers: 1,
+            sampler,
+            bind_group_layout,
+            bind_group,
+        }
+    }
+
+    pub fn bind_group_layout(&self) -> &BindGroupLayout {
+        &self.bind_group_layout
+    }
+
+    pub fn bind_group(&self) -> &BindGroup {
+        &self.bind_group
+    }
+
+    pub fn contains(&self, sprite_id: &S) -> bool {
+        self.packer.sprites.contains_key(sprite_id)
+    }
+
+    /// Index of the sprite in the offset table
+    pub fn index(&self, sprite_id: &S) -> Option<u32> {
+        self.packer
+            .sprites
+            .get(sprite_id)
+            .map(|sprite| sprite.index)
+    }
+
+    /// Size, nine-slice and animation of the sprite
+    pub fn info(&self, sprite_id: &S) -> Option<&SpriteInfo> {
+        self.packer
+            .sprites
+            .get(sprite_id)
+            .map(|sprite| &sprite.info)
+    }
+
+    /// Packs all frames of the sprite into the atlas. The texture is only updated in flush().
+    pub fn insert(&mut self, sprite_id: S, sprite: &Sprite) {
+        self.packer.insert(sprite_id, sprite);
+    }
+
+    /// Frees the atlas space of the sprite, returns false if the sprite is not registered.
+    pub fn remove(&mut self, sprite_id: &S) -> bool {
+        self.packer.remove(sprite_id)
+    }
+
+    /// Uploads pending frames and recreates the texture, tables and bind group.
+    pub fn flush(&mut self, device: &Device, queue: &Queue) {
+        let packer = &mut self.packer;
+        let layers = packer.allocators.len().max(1) as u32;
+        if packer.size != self.texture_size || layers != self.texture_layers {
+            // Keep the already uploaded frames, allocations never move when growing
+            let texture = Self::create_texture(device, packer.size, layers);
+            let mut cmds = device.create_command_encoder(&CommandEncoderDescriptor {
+                label: Some("STGI Atlas Copy Command Encoder"),
+            });
+            cmds.copy_texture_to_texture(
+                self.texture.as_image_copy(),
+                texture.as_image_copy(),
+                Extent3d {
+                    width: self.texture_size,
+                    height: self.texture_size,
+                    depth_or_array_layers: self.texture_layers,
+                },
+            );
+            queue.submit([cmds.finish()]);
+            self.texture = texture;
+            self.texture_size = packer.size;
+            self.texture_layers = layers;
+        }
+
+        for (atlas_index, rect, frame) in packer.take_uploads() {
+            queue.write_texture(
+                ImageCopyTexture {
+                    texture: &self.texture,
+                    mip_level: 0,
+                    origin: Origin3d {
+                        x: rect.min.x as u32,
+                        y: rect.min.y as u32,
+                        z: atlas_index,
+                    },
+                    aspect: TextureAspect::All,
+                },
+                &frame,
+                ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * frame.width()),
+                    rows_per_image: None,
+                },
+                Extent3d {
+                    width: frame.width(),
+                    height: frame.height(),
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        let atlas_size = packer.size as f32;
+        let mut offset_table = vec![SpriteInfo::zeroed(); packer.next_index.max(1) as usize];
+        let mut allocation_table: Vec<Allocation> = Vec::new();
+        for sprite in packer.sprites.values() {
+            offset_table[sprite.index as usize] = SpriteInfo {
+                offset: allocation_table.len() as u32,
+                ..sprite.info
+            };
+            for (atlas_index, _, rect) in &sprite.allocations {
+                allocation_table.push(Allocation {
+                    x_min: rect.min.x as f32 / atlas_size,
+                    x_max: rect.max.x as f32 / atlas_size,
+                    y_min: rect.min.y as f32 / atlas_size,
+                    y_max: rect.max.y as f32 / atlas_size,
+                    atlas_index: *atlas_index,
+                });
+            }
+        }
+        if allocation_table.is_empty() {
+            allocation_table.push(Allocation::zeroed());
+        }
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.texture,
+            &self.sampler,
+            &offset_table,
+            &allocation_table,
+        );
+    }
+
+    fn create_texture(device: &Device, size: u32, layers: u32) -> Texture {
+        device.create_texture(&TextureDescriptor {
+            label: Some("STGI Atlas Texture"),
+            size: Extent3d {
+                width: size,
+                height: size,
+                // The GL backend treats single layer textures as plain 2d textures, which breaks the array view
+                depth_or_array_layers: layers.max(2),
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8UnormSrgb,
+            usage: TextureUsages::COPY_DST
+                | TextureUsages::COPY_SRC
+                | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        })
+    }
+
+    fn create_bind_group(
+        device: &Device,
+        layout: &BindGroupLayout,
+        texture: &Texture,
+        sampler: &Sampler,
+        offset_table: &[SpriteInfo],
+        allocation_table: &[Allocation],
+    ) -> BindGroup {
+        let offset_table = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("STGI Offset Table"),
+            contents: bytemuck::cast_slice(offset_table),
+            usage: BufferUsages::STORAGE,
+        });
+        let allocation_table = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("STGI Allocation Table"),
+            contents: bytemuck::cast_slice(allocation_table),
+            usage: BufferUsages::STORAGE,
+        });
+        let view = texture.create_view(&TextureViewDescriptor {
+            label: Some("STGI Atlas Texture View"),
+            format: None,
+            dimension: Some(TextureViewDimension::D2Array),
+            aspect: TextureAspect::All,
+            ..Default::default()
+        });
+        device.create_bind_group(&BindGroupDescriptor {
+            layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: offset_table.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: allocation_table.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(&view),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::Sampler(sampler),
+                },
+            ],
+            label: Some("Stgi atlas bind group"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::sprite::{Picking, Playback};
+
+    fn square(size: u32) -> Sprite {
+        Sprite::Inanimate {
+            sprite: Frame::new(size, size),
+            picking: Picking::default(),
+        }
+    }
+
+    fn rects(packer: &AtlasPacker<u32>, sprite_id: u32) -> Vec<(u32, Rectangle)> {
+        packer.sprites[&sprite_id]
+            .allocations
+            .iter()
+            .map(|&(atlas_index, _, rect)| (atlas_index, rect))
+            .collect()
+    }
+
+    #[test]
+    fn allocation_survives_grow() {
+        let mut packer = AtlasPacker::new(128, 1024, 1);
+        packer.insert(0, &square(100));
+        let before = rects(&packer, 0);
+        packer.insert(1, &square(100));
+        assert_eq!(packer.size, 256);
+        assert_eq!(packer.allocators.len(), 1);
+        assert_eq!(rects(&packer, 0), before);
+        let (_, first) = before[0];
+        let (_, second) = rects(&packer, 1)[0];
+        assert!(first.intersection(&second).is_none());
+    }
+
+    #[test]
+    fn oversize_grows_to_next_power_of_two() {
+        let mut packer = AtlasPacker::new(128, 1024, 1);
+        packer.insert(0, &square(300));
+        assert_eq!(packer.size, 512);
+    }
+
+    #[test]
+    #[should_panic(expected = "Sprite too large")]
+    fn larger_than_max_size() {
+        let mut packer = AtlasPacker::new(128, 256, 1);
+        packer.insert(0, &square(300));
+    }
+
+    #[test]
+    fn layer_added_at_max_size() {
+        let mut packer = AtlasPacker::new(128, 128, 2);
+        packer.insert(0, &square(100));
+        packer.insert(1, &square(100));
+        assert_eq!(packer.size, 128);
+        assert_eq!(packer.allocators.len(), 2);
+        assert_eq!(rects(&packer, 0)[0].0, 0);
+        assert_eq!(rects(&packer, 1)[0].0, 1);
+    }
+
+    #[test]
+    fn removed_space_and_index_reused() {
+        let mut packer = AtlasPacker::new(128, 128, 1);
+        packer.insert(0, &square(100));
+        let before = rects(&packer, 0);
+        let index = packer.sprites[&0].index;
+        assert!(packer.remove(&0));
+        assert!(!packer.remove(&0));
+        // The frame of the removed sprite is not uploaded anymore
+        assert!(packer.pending_uploads.is_empty());
+
+        // Would not fit next to the removed sprite without growing
+        packer.insert(1, &square(100));
+        assert_eq!(rects(&packer, 1), before);
+        assert_eq!(packer.sprites[&1].index, index);
+        assert_eq!(packer.size, 128);
+    }
+
+    #[test]
+    fn replace() {
+        // Stgi::replace_sprite removes the old sprite and inserts the new one
+        let mut packer = AtlasPacker::new(128, 128, 1);
+        packer.insert(0, &square(60));
+        packer.insert(1, &square(60));
+        let index = packer.sprites[&0].index;
+        packer.remove(&0);
+        packer.insert(
+            0,
+            &Sprite::Animated {
+                sprite_sheet: Frame::new(120, 60),
+                sprite_width: 60,
+                frame_duration: Duration::from_millis(100),
+                playback: Playback::Loop,
+                picking: Picking::default(),
+            },
+        );
+        let sprite = &packer.sprites[&0];
+        assert_eq!(sprite.index, index);
+        assert_eq!(sprite.info.size, 2);
+        assert_eq!(rects(&packer, 0).len(), 2);
+        assert_eq!(packer.allocators.len(), 1);
+        // The frame of the other sprite and both new ones
+        assert_eq!(packer.pending_uploads.len(), 3);
+    }
+}
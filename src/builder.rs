@@ -1,33 +1,39 @@
-use std::{num::NonZeroU32, sync::Arc};
+use std::{num::NonZeroU32, sync::Arc, time::Duration};
 
 use ahash::{HashMap, HashSet};
 use fontdue::{Font, FontSettings};
-use guillotiere::{size2, Rectangle, SimpleAtlasAllocator};
-use image::{GenericImage, GenericImageView, ImageBuffer, Rgba};
+use image::{ImageBuffer, Rgba};
 use util::BufferInitDescriptor;
 use wgpu::{util::DeviceExt, *};
 
 use super::{
+    atlas::SpriteAtlas,
+    canvas::VirtualCanvas,
+    cpu::{CpuGlyphs, CpuSprites},
+    input::{EventQueue, DEFAULT_DOUBLE_CLICK_TIME, DEFAULT_DRAG_THRESHOLD},
+    scene::Scene,
+    software::SoftwareStgi,
+    sprite::{NineSlice, Picking, Playback, Sprite, DEFAULT_FRAME_DURATION},
     text::{FontId, TextRenderer},
-    Allocation, SpriteId, Stgi, UniformData, Vertex,
+    CursorPickingSlot, Instance, InstanceBuffer, SpriteId, Stgi, UniformData, Vertex,
+    CURSOR_PICKING_SLOTS,
 };
 
-enum LoadedSprite {
-    Animated {
-        sprite_sheet: ImageBuffer<Rgba<u8>, Vec<u8>>,
-        sprite_width: u32,
-    },
-    Inanimate {
-        sprite: ImageBuffer<Rgba<u8>, Vec<u8>>,
-    },
-}
-
 pub struct StgiBuilder<S: SpriteId, F: FontId> {
     fonts: HashMap<F, Font>,
     present_ids: HashSet<S>,
     // Sorted by the area of the sprite for packing performance
-    sprites: HashMap<S, LoadedSprite>,
+    sprites: HashMap<S, Sprite>,
     sprite_areas: Vec<(u32, S)>,
+    virtual_resolution: Option<(u32, u32)>,
+    double_click_time: Duration,
+    drag_threshold: f32,
+}
+
+impl<S: SpriteId, F: FontId> Default for StgiBuilder<S, F> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<S: SpriteId, F: FontId> StgiBuilder<S, F> {
@@ -37,67 +43,131 @@ impl<S: SpriteId, F: FontId> StgiBuilder<S, F> {
             present_ids: HashSet::default(),
             sprites: HashMap::default(),
             sprite_areas: Vec::new(),
+            virtual_resolution: None,
+            double_click_time: DEFAULT_DOUBLE_CLICK_TIME,
+            drag_threshold: DEFAULT_DRAG_THRESHOLD,
         }
     }
 
+    /// Renders the UI at a fixed low resolution, e.g. 320x180 for pixel art. All areas then use virtual pixels,
+    /// the result is upscaled by the largest integer factor that fits into the window and centered in it.
+    pub fn virtual_resolution(&mut self, width: u32, height: u32) {
+        assert!(
+            width > 0 && height > 0,
+            "Virtual resolution must be greater than 0"
+        );
+        self.virtual_resolution = Some((width, height));
+    }
+
+    /// The longest time between two clicks on an area that still counts as a double click, 500ms by default.
+    /// Measured with the timestamps passed to Stgi::set_mouse_button.
+    pub fn double_click_time(&mut self, time: Duration) {
+        self.double_click_time = time;
+    }
+
+    /// How far in pixels the cursor has to move while a button is pressed before the area is dragged, 4 by default.
+    /// In virtual pixels with a virtual resolution.
+    pub fn drag_threshold(&mut self, threshold: f32) {
+        self.drag_threshold = threshold;
+    }
+
     /// Adds a font to the builder, raw is the bytes of the font file
     pub fn add_font(&mut self, font_id: F, raw: &[u8]) {
         let font = Font::from_bytes(raw, FontSettings::default()).unwrap();
         self.fonts.insert(font_id, font);
     }
 
-    /// Adds a static sprite to the builder
-    pub fn add_inanimate_sprite(&mut self, sprite_id: S, sprite: ImageBuffer<Rgba<u8>, Vec<u8>>) {
+    /// Adds a sprite of any kind to the builder. The functions for the specific kinds use Picking::default(),
+    /// this one allows to pick the sprite differently.
+    pub fn add_sprite(&mut self, sprite_id: S, sprite: Sprite) {
         assert!(
             !self.present_ids.contains(&sprite_id),
             "Sprite ID: {:?} already present in the builder",
             sprite_id
         );
-        let (width, height) = sprite.dimensions();
-        assert!(
-            width > 0 && height > 0,
-            "Sprite dimensions must be greater than 0"
-        );
-        self.sprites
-            .insert(sprite_id.clone(), LoadedSprite::Inanimate { sprite });
+        sprite.validate();
+        let (width, height) = sprite.frame_size();
+        self.sprites.insert(sprite_id.clone(), sprite);
         self.sprite_areas.push((width * height, sprite_id.clone()));
         self.present_ids.insert(sprite_id);
     }
 
+    /// Adds a static sprite to the builder
+    pub fn add_inanimate_sprite(&mut self, sprite_id: S, sprite: ImageBuffer<Rgba<u8>, Vec<u8>>) {
+        self.add_sprite(
+            sprite_id,
+            Sprite::Inanimate {
+                sprite,
+                picking: Picking::default(),
+            },
+        );
+    }
+
+    /// Adds a nine-slice sprite to the builder, used for panels and buttons that can be resized freely.
+    pub fn add_nine_slice_sprite(
+        &mut self,
+        sprite_id: S,
+        sprite: ImageBuffer<Rgba<u8>, Vec<u8>>,
+        slice: NineSlice,
+    ) {
+        self.add_sprite(
+            sprite_id,
+            Sprite::NineSlice {
+                sprite,
+                slice,
+                picking: Picking::default(),
+            },
+        );
+    }
+
     /// Adds an animated sprite to the builder. All frames must be laid out horizontally in the sprite sheet.
     /// If the sprite width is not provided, it is assumed to be the same as the height of the sprite sheet.
+    /// The sprite loops with sprite::DEFAULT_FRAME_DURATION, use add_animated_sprite_with for other timings.
     pub fn add_animated_sprite(
         &mut self,
         sprite_id: S,
         sprite_sheet: ImageBuffer<Rgba<u8>, Vec<u8>>,
         sprite_width: Option<NonZeroU32>,
     ) {
-        assert!(
-            !self.present_ids.contains(&sprite_id),
-            "Sprite ID: {:?} already present in the builder",
-            sprite_id
-        );
-        let (sheet_width, height) = sprite_sheet.dimensions();
-        let width = sprite_width.map(|w| w.get()).unwrap_or(height);
-        assert!(
-            sheet_width > 0 && height > 0 && width > 0,
-            "Sprite sheet dimensions and sprite width must be greater than 0"
+        self.add_animated_sprite_with(
+            sprite_id,
+            sprite_sheet,
+            sprite_width,
+            DEFAULT_FRAME_DURATION,
+            Playback::Loop,
         );
-        self.sprites.insert(
-            sprite_id.clone(),
-            LoadedSprite::Animated {
+    }
+
+    /// Like add_animated_sprite, but every frame is shown for frame_duration (millisecond precision)
+    /// and the frames are played as given by playback, see Stgi::advance_animations.
+    pub fn add_animated_sprite_with(
+        &mut self,
+        sprite_id: S,
+        sprite_sheet: ImageBuffer<Rgba<u8>, Vec<u8>>,
+        sprite_width: Option<NonZeroU32>,
+        frame_duration: Duration,
+        playback: Playback,
+    ) {
+        let sprite_width = sprite_width
+            .map(|w| w.get())
+            .unwrap_or(sprite_sheet.height());
+        self.add_sprite(
+            sprite_id,
+            Sprite::Animated {
                 sprite_sheet,
-                sprite_width: width,
+                sprite_width,
+                frame_duration,
+                playback,
+                picking: Picking::default(),
             },
         );
-        self.sprite_areas.push((width * height, sprite_id.clone()));
-        self.present_ids.insert(sprite_id);
     }
 
     /// The min_glyph_atlas_area is the minimum area of the atlas texture (2d arraytexture) that will be used for the glyph atlas.
     /// This should be big enough to hold all the glyphs that will be used in the text rendering (all glyphs are packet into a glyph atlas).
     /// If the area is too small, the text rendering will fail.
     /// Suggested size: 8192 * 8192
+    /// The window size is always in physical pixels, also with a virtual resolution.
     pub fn build(
         &mut self,
         device: &Device,
@@ -107,169 +177,22 @@ impl<S: SpriteId, F: FontId> StgiBuilder<S, F> {
         surface_format: TextureFormat,
         min_glyph_atlas_area: u32,
     ) -> Stgi<S, F> {
-        let (atlas_frames, sprites) = self.create_atlas(device);
-
-        let mut sprite_indices: HashMap<S, u32> = HashMap::default();
-        let mut offset_table: Vec<[u32; 2]> = Vec::new();
-        let mut allocation_table: Vec<Allocation> = Vec::new();
-
-        let atlas_size = atlas_frames
-            .iter()
-            .map(|(_, texture)| texture.dimensions().0)
-            .max()
-            .unwrap();
-
-        let mut index = 0;
-        let mut offset = 0;
-        for (sprite_id, allocations) in sprites {
-            sprite_indices.insert(sprite_id, index);
-            index += 1;
-            offset_table.push([offset, allocations.len() as u32]);
-            offset += allocations.len() as u32;
-            for (atlas_index, rect) in allocations {
-                allocation_table.push(Allocation {
-                    x_min: rect.min.x as f32 / atlas_size as f32,
-                    x_max: rect.max.x as f32 / atlas_size as f32,
-                    y_min: rect.min.y as f32 / atlas_size as f32,
-                    y_max: rect.max.y as f32 / atlas_size as f32,
-                    atlas_index,
-                });
-            }
+        let mut atlas = SpriteAtlas::new(device);
+        self.sprite_areas
+            .sort_unstable_by_key(|(area, _)| -(*area as i32));
+        for (_, sprite_id) in &self.sprite_areas {
+            atlas.insert(sprite_id.clone(), self.sprites.get(sprite_id).unwrap());
         }
-
-        let offset_table = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("STGI Offset Table"),
-            contents: bytemuck::cast_slice(&offset_table),
-            usage: BufferUsages::STORAGE,
-        });
-        let allocation_table = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("STGI Allocation Table"),
-            contents: bytemuck::cast_slice(&allocation_table),
-            usage: BufferUsages::STORAGE,
-        });
-        let atlas_texture = device.create_texture(&TextureDescriptor {
-            label: Some("STGI Atlas Texture"),
-            size: wgpu::Extent3d {
-                width: atlas_size,
-                height: atlas_size,
-                depth_or_array_layers: atlas_frames.len() as u32,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: TextureDimension::D2,
-            format: TextureFormat::Rgba8UnormSrgb,
-            usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
-            view_formats: &[],
-        });
-        for (index, (_, texture)) in atlas_frames.into_iter().enumerate() {
-            queue.write_texture(
-                ImageCopyTexture {
-                    texture: &atlas_texture,
-                    mip_level: 0,
-                    origin: wgpu::Origin3d {
-                        x: 0,
-                        y: 0,
-                        z: index as u32,
-                    },
-                    aspect: TextureAspect::All,
-                },
-                &texture,
-                ImageDataLayout {
-                    offset: 0,
-                    bytes_per_row: Some(4 * texture.dimensions().0),
-                    rows_per_image: None,
-                },
-                Extent3d {
-                    width: texture.dimensions().0,
-                    height: texture.dimensions().1,
-                    depth_or_array_layers: 1,
-                },
-            );
+        atlas.flush(device, queue);
+        let mut cpu_sprites = CpuSprites::new();
+        for (sprite_id, sprite) in &self.sprites {
+            cpu_sprites.insert(sprite_id.clone(), sprite);
         }
-        let atlas_view = atlas_texture.create_view(&TextureViewDescriptor {
-            label: Some("STGI Atlas Texture View"),
-            format: None,
-            dimension: Some(TextureViewDimension::D2Array),
-            aspect: TextureAspect::All,
-            ..Default::default()
-        });
-        let atlas_sampler = device.create_sampler(&SamplerDescriptor {
-            label: Some("STGI Atlas Sampler"),
-            address_mode_u: AddressMode::ClampToEdge,
-            address_mode_v: AddressMode::ClampToEdge,
-            address_mode_w: AddressMode::ClampToEdge,
-            mag_filter: FilterMode::Nearest,
-            min_filter: FilterMode::Nearest,
-            mipmap_filter: FilterMode::Nearest,
-            ..Default::default()
-        });
-        let atlas_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-            entries: &[
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::VERTEX,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStages::VERTEX,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Texture {
-                        multisampled: false,
-                        view_dimension: TextureViewDimension::D2Array,
-                        sample_type: TextureSampleType::Float { filterable: true },
-                    },
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 3,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
-                    count: None,
-                },
-            ],
-            label: Some("Stgi atlas bind group layout"),
-        });
-        let atlas_bind_group = device.create_bind_group(&BindGroupDescriptor {
-            layout: &atlas_bind_group_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: offset_table.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: allocation_table.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: BindingResource::TextureView(&atlas_view),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: BindingResource::Sampler(&atlas_sampler),
-                },
-            ],
-            label: Some("Stgi atlas bind group"),
-        });
+
         let index_buffer_size = 6;
         let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("STGI Index Buffer"),
-            contents: bytemuck::cast_slice(&[0u16, 1, 2, 0, 2, 3]),
+            contents: bytemuck::cast_slice(&[0u32, 1, 2, 0, 2, 3]),
             usage: BufferUsages::INDEX,
         });
         let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -291,8 +214,23 @@ impl<S: SpriteId, F: FontId> StgiBuilder<S, F> {
             usage: BufferUsages::VERTEX,
         });
 
+        let canvas = self.virtual_resolution.map(|(width, height)| {
+            VirtualCanvas::new(
+                device,
+                surface_format,
+                width,
+                height,
+                window_width,
+                window_height,
+            )
+        });
+        // Areas are laid out and picked on the virtual canvas if there is one
+        let (window_width, window_height) = canvas
+            .as_ref()
+            .map_or((window_width, window_height), |canvas| canvas.size());
+
         let uniform_data = UniformData {
-            current_frame: 0,
+            time: 0,
             window_width: window_width as f32,
             window_height: window_height as f32,
         };
@@ -330,7 +268,7 @@ impl<S: SpriteId, F: FontId> StgiBuilder<S, F> {
         });
         let render_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
             label: Some("Stgi render pipeline layout"),
-            bind_group_layouts: &[&atlas_bind_group_layout, &uniform_bind_group_layout],
+            bind_group_layouts: &[atlas.bind_group_layout(), &uniform_bind_group_layout],
             push_constant_ranges: &[],
         });
         let render_pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
@@ -381,7 +319,7 @@ impl<S: SpriteId, F: FontId> StgiBuilder<S, F> {
             mip_level_count: 1,
             sample_count: 1,
             dimension: TextureDimension::D2,
-            format: TextureFormat::R32Uint,
+            format: TextureFormat::Rgba32Uint,
             usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
             view_formats: &[],
         });
@@ -394,7 +332,7 @@ impl<S: SpriteId, F: FontId> StgiBuilder<S, F> {
         let cursor_picking_render_pipeline_layout =
             device.create_pipeline_layout(&PipelineLayoutDescriptor {
                 label: Some("STGI Cursor Picking Pipeline Layout"),
-                bind_group_layouts: &[&atlas_bind_group_layout, &uniform_bind_group_layout],
+                bind_group_layouts: &[atlas.bind_group_layout(), &uniform_bind_group_layout],
                 push_constant_ranges: &[],
             });
         let cursor_picking_render_pipeline =
@@ -411,7 +349,7 @@ impl<S: SpriteId, F: FontId> StgiBuilder<S, F> {
                     module: &cursor_picking_render_shader,
                     entry_point: "fs_main",
                     targets: &[Some(ColorTargetState {
-                        format: TextureFormat::R32Uint,
+                        format: TextureFormat::Rgba32Uint,
                         blend: None,
                         write_mask: ColorWrites::ALL,
                     })],
@@ -441,17 +379,21 @@ impl<S: SpriteId, F: FontId> StgiBuilder<S, F> {
             contents: bytemuck::cast_slice(&cursor_pos_uniform),
             usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
         });
-        let cursor_picking_result_staging_buffer =
-            Arc::new(device.create_buffer(&BufferDescriptor {
-                label: Some("STGI cursor picking result staging buffer"),
-                size: 4,
-                usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
-                mapped_at_creation: false,
-            }));
+        let cursor_picking_slots = (0..CURSOR_PICKING_SLOTS)
+            .map(|_| CursorPickingSlot {
+                buffer: Arc::new(device.create_buffer(&BufferDescriptor {
+                    label: Some("STGI cursor picking result staging buffer"),
+                    size: 16,
+                    usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                })),
+                in_flight: false,
+            })
+            .collect();
         let cursor_picking_result_storage_buffer =
             device.create_buffer_init(&BufferInitDescriptor {
                 label: Some("STGI cursor picking result storage buffer"),
-                contents: &[0u8; 4],
+                contents: &[0u8; 16],
                 usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
             });
         let cursor_picking_compute_bind_group_layout =
@@ -538,36 +480,41 @@ impl<S: SpriteId, F: FontId> StgiBuilder<S, F> {
             surface_format,
             min_glyph_atlas_area,
             &uniform_bind_group_layout,
+            atlas.bind_group_layout(),
             self.fonts.clone(),
         );
 
         Stgi {
             text_renderer,
-            sprite_indices,
-            offset_table,
-            allocation_table,
-            atlas_texture,
-            atlas_view,
-            atlas_sampler,
-            atlas_bind_group,
+            atlas,
+            cpu_sprites,
+            cpu_glyphs: CpuGlyphs::new(self.fonts.clone()),
 
             index_buffer,
             index_buffer_size,
             vertex_buffer,
-            instance_buffers: vec![None, None, None, None],
+            instance_buffer: InstanceBuffer {
+                staging: Vec::new(),
+                buffer: device.create_buffer(&BufferDescriptor {
+                    label: Some("Instance Buffer"),
+                    size: 128 * std::mem::size_of::<Instance>() as u64,
+                    usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+                capacity: 128,
+            },
+            draw_commands: Vec::new(),
+            picking_commands: Vec::new(),
+            drawn_areas: HashMap::default(),
             render_pipeline,
 
             uniform_data,
             uniform_buffer,
             uniform_bind_group,
 
-            next_area_id: NonZeroU32::new(1).unwrap(),
-            ui_areas: HashMap::default(),
-            dirty_areas: Vec::new(),
-            areas_to_remove: Vec::new(),
-            recently_cleared: false,
-
-            animation_frame: 0,
+            scene: Scene::new(window_width as f32, window_height as f32),
+            canvas,
+            format: surface_format,
             cursor_picking_texture,
             cursor_picking_texture_view,
             cursor_picking_render_pipeline,
@@ -578,129 +525,33 @@ impl<S: SpriteId, F: FontId> StgiBuilder<S, F> {
             cursor_picking_compute_bind_group_layout,
             cursor_picking_compute_bind_group,
             cursor_picking_result: None,
-            cursor_picking_result_staging_buffer,
+            cursor_picking_slots,
+            cursor_picking_slot: None,
+            cursor_picking_frame: 0,
+            cursor_picking_result_frame: 0,
             cursor_picking_result_storage_buffer,
             cursor_picking_result_sender,
             cursor_picking_result_receiver,
+            events: EventQueue::new(self.double_click_time, self.drag_threshold),
         }
     }
 
-    /// Allocates the sprites into the atlas array and also copies the sprite data into the atlas textures (cpu side)
-    fn create_atlas(
-        &mut self,
-        device: &Device,
-    ) -> (
-        Vec<(SimpleAtlasAllocator, ImageBuffer<Rgba<u8>, Vec<u8>>)>,
-        HashMap<S, Vec<(u32, Rectangle)>>,
-    ) {
+    /// Builds a SoftwareStgi, which renders on the CPU and needs no wgpu device.
+    /// The image has the given size, or the virtual resolution if there is one, which is not upscaled.
+    /// The sprites are packed into an atlas in memory in the same order as for build, and sampled from there.
+    pub fn build_software(&mut self, width: u32, height: u32) -> SoftwareStgi<S, F> {
+        let (width, height) = self.virtual_resolution.unwrap_or((width, height));
         self.sprite_areas
             .sort_unstable_by_key(|(area, _)| -(*area as i32));
-        let mut atlas_size = 128u32;
-        let max_texture_size = device.limits().max_texture_dimension_2d;
-        let mut allocators: Vec<(SimpleAtlasAllocator, ImageBuffer<Rgba<u8>, Vec<u8>>)> =
-            Vec::new();
-        let mut sprites: HashMap<S, Vec<(u32, Rectangle)>> = HashMap::default();
+        let mut sprites = CpuSprites::with_colors();
         for (_, sprite_id) in &self.sprite_areas {
-            let sprite = self.sprites.get(sprite_id).unwrap();
-            let frames = match sprite {
-                LoadedSprite::Inanimate { sprite } => {
-                    vec![sprite.view(0, 0, sprite.width(), sprite.height())]
-                }
-                LoadedSprite::Animated {
-                    sprite_sheet,
-                    sprite_width,
-                } => {
-                    let (height, width) = (sprite_sheet.height(), *sprite_width);
-                    let mut frames = Vec::with_capacity((sprite_sheet.width() / width) as usize);
-                    for frame_index in 0..(sprite_sheet.width() / width) {
-                        frames.push(sprite_sheet.view(frame_index * width, 0, width, height));
-                    }
-                    frames
-                }
-            };
-
-            let mut allocations: Vec<(u32, Rectangle)> = Vec::new();
-            'outer: for sprite in frames {
-                let (width, height) = sprite.dimensions();
-                // Try to pack the sprite into one of the existing allocators
-                for (index, (allocator, texture)) in allocators.iter_mut().enumerate() {
-                    if let Some(rect) = allocator.allocate(size2(width as i32, height as i32)) {
-                        allocations.push((index as u32, rect));
-                        for y in 0..height {
-                            for x in 0..width {
-                                texture.put_pixel(
-                                    rect.min.x as u32 + x,
-                                    rect.min.y as u32 + y,
-                                    sprite.get_pixel(x, y),
-                                );
-                            }
-                        }
-                        continue 'outer;
-                    }
-                }
-                // Try to grow the last allocator and then pack the sprite
-                if !allocators.is_empty() {
-                    let index = allocators.len() - 1;
-                    if let Some((allocator, texture)) = allocators.last_mut() {
-                        loop {
-                            let size = allocator.size().width as u32;
-                            let new_size = (size * 2).min(max_texture_size).min(65536);
-                            if new_size > size {
-                                atlas_size = new_size;
-                                allocator.grow(size2(new_size as i32, new_size as i32));
-                                let mut new_texture = ImageBuffer::new(new_size, new_size);
-                                new_texture.copy_from(texture, 0, 0).unwrap();
-                                *texture = new_texture;
-                                if let Some(rect) =
-                                    allocator.allocate(size2(width as i32, height as i32))
-                                {
-                                    allocations.push((index as u32, rect));
-                                    for y in 0..height {
-                                        for x in 0..width {
-                                            texture.put_pixel(
-                                                rect.min.x as u32 + x,
-                                                rect.min.y as u32 + y,
-                                                sprite.get_pixel(x, y),
-                                            );
-                                        }
-                                    }
-                                    continue 'outer;
-                                }
-                            } else {
-                                break;
-                            }
-                        }
-                    }
-                }
-
-                // Create a new allocator and pack the sprite
-                atlas_size = atlas_size
-                    .max(width.max(height))
-                    .next_power_of_two()
-                    .min(max_texture_size);
-                if atlas_size < width.max(height) {
-                    panic!("Sprite too large to fit into a texture");
-                }
-                let mut allocator =
-                    SimpleAtlasAllocator::new(size2(atlas_size as i32, atlas_size as i32));
-                let rect = allocator
-                    .allocate(size2(width as i32, height as i32))
-                    .unwrap();
-                let mut texture = ImageBuffer::new(atlas_size, atlas_size);
-                for y in 0..height {
-                    for x in 0..width {
-                        texture.put_pixel(
-                            rect.min.x as u32 + x,
-                            rect.min.y as u32 + y,
-                            sprite.get_pixel(x, y),
-                        );
-                    }
-                }
-                allocators.push((allocator, texture));
-                allocations.push((allocators.len() as u32 - 1, rect));
-            }
-            sprites.insert(sprite_id.clone(), allocations);
+            sprites.insert(sprite_id.clone(), &self.sprites[sprite_id]);
         }
-        (allocators, sprites)
+        SoftwareStgi::new(
+            Scene::new(width as f32, height as f32),
+            sprites,
+            CpuGlyphs::new(self.fonts.clone()),
+            self.virtual_resolution.is_some(),
+        )
     }
 }
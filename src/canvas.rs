@@ -0,0 +1,202 @@
+// With a virtual resolution all areas are rendered into an offscreen texture of that size first.
+// That texture is then upscaled by the largest integer factor that fits into the window and centered,
+// the remaining space (letterbox) is left as it is in the render pass of the caller.
+
+use wgpu::*;
+
+pub(crate) struct VirtualCanvas {
+    width: u32,
+    height: u32,
+    view: TextureView,
+    bind_group: BindGroup,
+    pipeline: RenderPipeline,
+    // x, y, width, height in physical pixels
+    viewport: [f32; 4],
+}
+
+impl VirtualCanvas {
+    pub fn new(
+        device: &Device,
+        format: TextureFormat,
+        width: u32,
+        height: u32,
+        physical_width: u32,
+        physical_height: u32,
+    ) -> Self {
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("STGI Virtual Canvas Texture"),
+            size: Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsages::RENDER_ATTACHMENT
+                | TextureUsages::TEXTURE_BINDING
+                | TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("STGI Virtual Canvas Sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Nearest,
+            min_filter: FilterMode::Nearest,
+            mipmap_filter: FilterMode::Nearest,
+            ..Default::default()
+        });
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("STGI Virtual Canvas Bind Group Layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: TextureViewDimension::D2,
+                        sample_type: TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("STGI Virtual Canvas Bind Group"),
+            layout: &bind_group_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&view),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::Sampler(&sampler),
+                },
+            ],
+        });
+
+        let shader = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("Stgi blit shader"),
+            source: ShaderSource::Wgsl(include_str!("shaders/blit.wgsl").into()),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("STGI Blit Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: Some("STGI Blit Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+                compilation_options: Default::default(),
+            },
+            primitive: PrimitiveState {
+                topology: PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: FrontFace::Cw,
+                cull_mode: None,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+                unclipped_depth: false,
+            },
+            depth_stencil: None,
+            multisample: MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            fragment: Some(FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(ColorTargetState {
+                    format,
+                    // The canvas is cleared to transparent and alpha blended into, so its colors are premultiplied
+                    blend: Some(BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+                    write_mask: ColorWrites::ALL,
+                })],
+                compilation_options: PipelineCompilationOptions::default(),
+            }),
+            multiview: None,
+            cache: None,
+        });
+
+        let mut canvas = Self {
+            width,
+            height,
+            view,
+            bind_group,
+            pipeline,
+            viewport: [0.0, 0.0, width as f32, height as f32],
+        };
+        canvas.resize(physical_width, physical_height);
+        canvas
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn view(&self) -> &TextureView {
+        &self.view
+    }
+
+    /// Recalculates the integer scale and the letterbox for the new window size.
+    /// A minimized window reports a size of zero, the canvas keeps its last viewport then.
+    pub fn resize(&mut self, physical_width: u32, physical_height: u32) {
+        if physical_width == 0 || physical_height == 0 {
+            return;
+        }
+        let scale = (physical_width / self.width)
+            .min(physical_height / self.height)
+            .max(1);
+        // If the window is smaller than the canvas, it is squashed to fit
+        let width = (self.width * scale).min(physical_width).max(1);
+        let height = (self.height * scale).min(physical_height).max(1);
+        self.viewport = [
+            ((physical_width - width) / 2) as f32,
+            ((physical_height - height) / 2) as f32,
+            width as f32,
+            height as f32,
+        ];
+    }
+
+    /// Maps a position in physical window pixels to the canvas, None if it is in the letterbox
+    pub fn to_virtual(&self, x: u32, y: u32) -> Option<(u32, u32)> {
+        let (x, y) = self.project(x, y);
+        if x < 0.0 || y < 0.0 || x >= self.width as f32 || y >= self.height as f32 {
+            return None;
+        }
+        Some((x as u32, y as u32))
+    }
+
+    /// Maps a position in physical window pixels to the canvas, positions in the letterbox end up outside of it
+    pub fn project(&self, x: u32, y: u32) -> (f32, f32) {
+        let [viewport_x, viewport_y, viewport_width, viewport_height] = self.viewport;
+        let x = (x as f32 - viewport_x) / viewport_width * self.width as f32;
+        let y = (y as f32 - viewport_y) / viewport_height * self.height as f32;
+        (x, y)
+    }
+
+    /// Draws the canvas into the render pass of the window
+    pub fn blit(&self, render_pass: &mut RenderPass) {
+        let [x, y, width, height] = self.viewport;
+        render_pass.set_viewport(x, y, width, height, 0.0, 1.0);
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
@@ -0,0 +1,476 @@
+// CPU copies of the sprites and glyphs, so areas can be rendered and picked without a GPU.
+// Sampling mirrors the shaders, that way the CPU and the GPU agree on every pixel.
+
+use std::{cell::RefCell, ops::Range};
+
+use ahash::HashMap;
+use fontdue::{
+    layout::{CoordinateSystem, Layout},
+    Font,
+};
+use image::{imageops, Rgba, RgbaImage};
+
+use super::{
+    atlas::AtlasPacker,
+    scene::{InternalUiArea, Scene},
+    software::decode,
+    sprite::{Picking, Sprite},
+    text::{effect_offset, layout_text, span_sprite, span_style, text_layers, FontId, GlyphKind},
+    AreaAnimation, Hit, SpriteId, SpriteInfo, ZOrder,
+};
+
+/// The pixels of every animation frame of a sprite
+enum Frames {
+    /// Only the alpha channel, row major, which is all picking needs
+    Alpha(Vec<Vec<u8>>),
+    /// (atlas_index, x, y) of every frame in the CpuAtlas
+    Atlas(Vec<(u32, u32, u32)>),
+}
+
+struct CpuSprite {
+    info: SpriteInfo,
+    frames: Frames,
+    picking: Picking,
+}
+
+/// The layers of the sprite atlas in memory, packed like the atlas texture of Stgi.
+/// The software renderer samples from it, so it renders the packed frames like the GPU does.
+struct CpuAtlas<S: SpriteId> {
+    packer: AtlasPacker<S>,
+    layers: Vec<RgbaImage>,
+}
+
+impl<S: SpriteId> CpuAtlas<S> {
+    /// Packs the frames of the sprite and writes them into the layers, returns where they were packed
+    fn insert(&mut self, sprite_id: S, sprite: &Sprite) -> Vec<(u32, u32, u32)> {
+        self.packer.remove(&sprite_id);
+        self.packer.insert(sprite_id.clone(), sprite);
+        // Allocations never move when the layers grow
+        let size = self.packer.size();
+        for layer in &mut self.layers {
+            if layer.width() != size {
+                let mut grown = RgbaImage::new(size, size);
+                imageops::replace(&mut grown, layer, 0, 0);
+                *layer = grown;
+            }
+        }
+        self.layers
+            .resize_with(self.packer.layers() as usize, || RgbaImage::new(size, size));
+        for (atlas_index, rect, frame) in self.packer.take_uploads() {
+            let layer = &mut self.layers[atlas_index as usize];
+            imageops::replace(layer, &frame, rect.min.x as i64, rect.min.y as i64);
+        }
+        self.packer.positions(&sprite_id).unwrap_or_default()
+    }
+}
+
+pub(crate) struct CpuSprites<S: SpriteId> {
+    sprites: HashMap<S, CpuSprite>,
+    // Only with colors
+    atlas: Option<CpuAtlas<S>>,
+}
+
+impl<S: SpriteId> CpuSprites<S> {
+    /// Keeps only the alpha of the sprites, enough for picking
+    pub fn new() -> Self {
+        Self {
+            sprites: HashMap::default(),
+            atlas: None,
+        }
+    }
+
+    /// Keeps the full colors of the sprites packed into an atlas, for rendering them.
+    /// The atlas grows within the default limits of wgpu.
+    pub fn with_colors() -> Self {
+        Self {
+            sprites: HashMap::default(),
+            atlas: Some(CpuAtlas {
+                packer: AtlasPacker::with_limits(&wgpu::Limits::default()),
+                layers: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn contains(&self, sprite_id: &S) -> bool {
+        self.sprites.contains_key(sprite_id)
+    }
+
+    /// Replaces the sprite if it is already present
+    pub fn insert(&mut self, sprite_id: S, sprite: &Sprite) {
+        let frames = sprite.frames();
+        let info = sprite.info(frames.len() as u32);
+        let frames = match &mut self.atlas {
+            Some(atlas) => Frames::Atlas(atlas.insert(sprite_id.clone(), sprite)),
+            None => Frames::Alpha(
+                frames
+                    .iter()
+                    .map(|frame| frame.pixels().map(|pixel| pixel[3]).collect())
+                    .collect(),
+            ),
+        };
+        self.sprites.insert(
+            sprite_id,
+            CpuSprite {
+                info,
+                frames,
+                picking: sprite.picking(),
+            },
+        );
+    }
+
+    pub fn remove(&mut self, sprite_id: &S) -> bool {
+        if let Some(atlas) = &mut self.atlas {
+            atlas.packer.remove(sprite_id);
+        }
+        self.sprites.remove(sprite_id).is_some()
+    }
+
+    /// A texel of an animation frame. Without colors, the texel is white with the alpha of the sprite.
+    fn texel(&self, sprite: &CpuSprite, frame: u32, x: u32, y: u32) -> Rgba<u8> {
+        match (&sprite.frames, &self.atlas) {
+            (Frames::Alpha(frames), _) => {
+                let alpha = frames[frame as usize][(y * sprite.info.width as u32 + x) as usize];
+                Rgba([255, 255, 255, alpha])
+            }
+            (Frames::Atlas(positions), Some(atlas)) => {
+                let (atlas_index, frame_x, frame_y) = positions[frame as usize];
+                *atlas.layers[atlas_index as usize].get_pixel(frame_x + x, frame_y + y)
+            }
+            (Frames::Atlas(_), None) => unreachable!("Frames in an atlas without one"),
+        }
+    }
+
+    /// The picking of an area, which overrides the picking of its sprite
+    pub fn picking<F: FontId>(&self, area: &InternalUiArea<S, F>) -> Picking {
+        area.area
+            .picking
+            .or_else(|| {
+                let sprite = self.sprites.get(area.sprite()?)?;
+                Some(sprite.picking)
+            })
+            .unwrap_or_default()
+    }
+
+    /// The animation frame the sprite of an area shows, None if the area has no (registered) sprite
+    pub fn frame<F: FontId>(&self, area: &InternalUiArea<S, F>, time: u32) -> Option<u32> {
+        let sprite = self.sprites.get(area.sprite()?)?;
+        Some(area_frame(&sprite.info, area.area.animation, time))
+    }
+
+    /// Samples the sprite of an area at a position in window pixels, like the sprite shaders do.
+    /// time is the animation clock in milliseconds. The color of the area is not applied.
+    /// Without colors, the texel is white with the alpha of the sprite.
+    /// Returns None if the area has no (registered) sprite.
+    pub fn sample<F: FontId>(
+        &self,
+        area: &InternalUiArea<S, F>,
+        time: u32,
+        x: f32,
+        y: f32,
+    ) -> Option<Rgba<u8>> {
+        let sprite = self.sprites.get(area.sprite()?)?;
+        let info = &sprite.info;
+        let frame = area_frame(info, area.area.animation, time);
+        let resolved = &area.resolved;
+        let tile = info.slice_mode == 1;
+        let position_x = slice_axis(
+            x - resolved.x_min,
+            resolved.x_max - resolved.x_min,
+            info.width,
+            info.slice_left,
+            info.slice_right,
+            tile,
+        );
+        let position_y = slice_axis(
+            y - resolved.y_min,
+            resolved.y_max - resolved.y_min,
+            info.height,
+            info.slice_top,
+            info.slice_bottom,
+            tile,
+        );
+        // Stay inside of the sprite, like the shader stays inside of the allocation
+        let texel_x = position_x.clamp(0.0, info.width - 0.001) as u32;
+        let texel_y = position_y.clamp(0.0, info.height - 0.001) as u32;
+        Some(self.texel(sprite, frame, texel_x, texel_y))
+    }
+}
+
+/// Computes the frame of an animated sprite that is shown after the given amount of milliseconds
+fn animation_frame(info: &SpriteInfo, elapsed: u32) -> u32 {
+    let frames = info.size;
+    let step = elapsed / info.frame_duration.max(1);
+    match info.playback {
+        // Once
+        1 => step.min(frames - 1),
+        // Ping-pong
+        2 => {
+            if frames < 2 {
+                return 0;
+            }
+            let period = 2 * frames - 2;
+            let t = step % period;
+            if t < frames {
+                t
+            } else {
+                period - t
+            }
+        }
+        // Reverse
+        3 => frames - 1 - step % frames,
+        // Loop
+        _ => step % frames,
+    }
+}
+
+/// Computes the frame an area shows, based on its own animation state
+fn area_frame(info: &SpriteInfo, animation: AreaAnimation, time: u32) -> u32 {
+    let (mode, value) = animation.to_instance_data();
+    match mode {
+        // Paused
+        1 => animation_frame(info, value),
+        // Fixed frame
+        2 => value.min(info.size - 1),
+        // Playing
+        _ => animation_frame(info, time.saturating_sub(value)),
+    }
+}
+
+/// Maps a position inside the area to a position inside the sprite along one axis, see slice_axis in render.wgsl
+fn slice_axis(
+    position: f32,
+    area_size: f32,
+    sprite_size: f32,
+    inset_min: f32,
+    inset_max: f32,
+    tile: bool,
+) -> f32 {
+    let scale = (area_size / (inset_min + inset_max).max(0.0001)).min(1.0);
+    let border_min = inset_min * scale;
+    let border_max = inset_max * scale;
+    if position < border_min {
+        return position / scale;
+    }
+    if position >= area_size - border_max {
+        return sprite_size - (area_size - position) / scale;
+    }
+    let center = (sprite_size - inset_min - inset_max).max(0.0001);
+    if tile {
+        // The % of WGSL truncates like the one of Rust
+        return inset_min + (position - border_min) % center;
+    }
+    inset_min + (position - border_min) / (area_size - border_min - border_max) * center
+}
+
+/// Glyphs rasterized on the CPU, the counterpart to the glyph atlas of the TextRenderer
+pub(crate) struct CpuGlyphs<F: FontId> {
+    fonts: HashMap<F, Font>,
+    // (font_id, font_size, character) -> coverage bitmap of the glyph.
+    // Cells, so picking can rasterize and lay out glyphs behind a shared reference.
+    bitmaps: RefCell<HashMap<(F, u16, char), Vec<u8>>>,
+    layout: RefCell<Layout<usize>>,
+}
+
+impl<F: FontId> CpuGlyphs<F> {
+    pub fn new(fonts: HashMap<F, Font>) -> Self {
+        Self {
+            fonts,
+            bitmaps: RefCell::default(),
+            layout: RefCell::new(Layout::new(CoordinateSystem::PositiveYDown)),
+        }
+    }
+
+    /// Lays out the text of an area and calls f for every pixel covered by its shadow, outline, glyphs or inline
+    /// sprites, with the pixel position, the coverage and the color of the layer, from bottom to top.
+    /// Only pixels inside of the given ranges are visited, the clip rect is ignored.
+    /// time is the animation clock in milliseconds, for text effects and animated inline sprites.
+    pub fn for_each_pixel<S: SpriteId>(
+        &self,
+        area: &InternalUiArea<S, F>,
+        sprites: &CpuSprites<S>,
+        time: u32,
+        (xs, ys): (Range<i32>, Range<i32>),
+        mut f: impl FnMut(i32, i32, u8, [f32; 4]),
+    ) {
+        let Some(text) = &area.area.text else {
+            return;
+        };
+        let glyphs = layout_text(
+            &mut self.layout.borrow_mut(),
+            &self.fonts,
+            text,
+            &area.resolved,
+            |sprite| {
+                let info = &sprites.sprites.get(sprite)?.info;
+                Some((info.width, info.height))
+            },
+        );
+        let mut bitmaps = self.bitmaps.borrow_mut();
+        for layer in text_layers(text) {
+            let dilation = layer.dilation as f32;
+            for glyph in &glyphs {
+                if glyph.width == 0 || glyph.height == 0 {
+                    continue;
+                }
+                let style = span_style(text, glyph.span);
+                let (effect_x, effect_y) = match glyph.kind {
+                    GlyphKind::Underline => (0.0, 0.0),
+                    _ => effect_offset(style.effect.to_vertex_data(glyph.index, style.size), time),
+                };
+                // Pixels whose center lies inside of the glyph quad, like the GPU rasterizes it
+                let x = glyph.x + layer.offset_x + effect_x;
+                let y = glyph.y + layer.offset_y + effect_y;
+                let x_min = ((x - dilation - 0.5).ceil() as i32).max(xs.start);
+                let x_max = ((x + glyph.width as f32 + dilation - 0.5).ceil() as i32).min(xs.end);
+                let y_min = ((y - dilation - 0.5).ceil() as i32).max(ys.start);
+                let y_max = ((y + glyph.height as f32 + dilation - 0.5).ceil() as i32).min(ys.end);
+                if x_min >= x_max || y_min >= y_max {
+                    continue;
+                }
+                // The opacity of the area and its parents fades every layer
+                let [r, g, b, a] = layer.color.unwrap_or(style.color);
+                let color = [r, g, b, a * area.resolved.opacity];
+
+                if glyph.kind == GlyphKind::Sprite {
+                    // Inline sprites have no outline or shadow
+                    if layer.color.is_some() {
+                        continue;
+                    }
+                    let Some(sprite) = span_sprite(text, glyph.span)
+                        .and_then(|sprite| sprites.sprites.get(sprite))
+                    else {
+                        continue;
+                    };
+                    let info = &sprite.info;
+                    let frame = animation_frame(info, time);
+                    // Like sprite_texel in text_render.wgsl
+                    let scale_x = info.width / glyph.width as f32;
+                    let scale_y = info.height / glyph.height as f32;
+                    for pixel_y in y_min..y_max {
+                        let texel_y = ((pixel_y as f32 + 0.5 - y) * scale_y + 0.001).floor();
+                        for pixel_x in x_min..x_max {
+                            let texel_x = ((pixel_x as f32 + 0.5 - x) * scale_x + 0.001).floor();
+                            if texel_x < 0.0
+                                || texel_y < 0.0
+                                || texel_x >= info.width
+                                || texel_y >= info.height
+                            {
+                                continue;
+                            }
+                            let texel =
+                                sprites.texel(sprite, frame, texel_x as u32, texel_y as u32);
+                            let [r, g, b, _] = decode(texel);
+                            if texel[3] > 0 {
+                                f(
+                                    pixel_x,
+                                    pixel_y,
+                                    texel[3],
+                                    [r * color[0], g * color[1], b * color[2], color[3]],
+                                );
+                            }
+                        }
+                    }
+                    continue;
+                }
+
+                // None for solid underlines
+                let bitmap = match glyph.kind {
+                    GlyphKind::Character(character) => {
+                        let Some(font) = self.fonts.get(&style.font) else {
+                            continue;
+                        };
+                        Some(
+                            &*bitmaps
+                                .entry((style.font, style.size, character))
+                                .or_insert_with(|| font.rasterize(character, style.size as f32).1),
+                        )
+                    }
+                    _ => None,
+                };
+                // Like coverage in text_render.wgsl
+                let texel_coverage = |texel_x: i32, texel_y: i32| {
+                    if texel_x < 0
+                        || texel_y < 0
+                        || texel_x >= glyph.width as i32
+                        || texel_y >= glyph.height as i32
+                    {
+                        return 0;
+                    }
+                    bitmap.map_or(255, |bitmap| {
+                        bitmap[texel_y as usize * glyph.width + texel_x as usize]
+                    })
+                };
+                let radius = layer.dilation as i32;
+                for pixel_y in y_min..y_max {
+                    let texel_y = (pixel_y as f32 + 0.5 - y).floor() as i32;
+                    for pixel_x in x_min..x_max {
+                        let texel_x = (pixel_x as f32 + 0.5 - x).floor() as i32;
+                        let mut coverage = 0;
+                        for offset_y in -radius..=radius {
+                            for offset_x in -radius..=radius {
+                                coverage = coverage
+                                    .max(texel_coverage(texel_x + offset_x, texel_y + offset_y));
+                            }
+                        }
+                        if coverage > 0 {
+                            f(pixel_x, pixel_y, coverage, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// All areas at a pixel from top to bottom, picked like the cursor picking shaders do: sprites count where their
+/// alpha reaches the picking threshold, text wherever a glyph, its outline, its shadow or an inline sprite covers
+/// the pixel.
+/// Text overflowing its area is hit outside of it too, like it is drawn.
+/// Non-pickable areas and texts are skipped.
+/// Uses the areas as of the last update.
+pub(crate) fn areas_at<'a, S: SpriteId, F: FontId>(
+    scene: &'a Scene<S, F>,
+    sprites: &'a CpuSprites<S>,
+    glyphs: &'a CpuGlyphs<F>,
+    x: u32,
+    y: u32,
+) -> impl Iterator<Item = (Hit, ZOrder)> + 'a {
+    let time = scene.animation_time().as_millis() as u32;
+    let (center_x, center_y) = (x as f32 + 0.5, y as f32 + 0.5);
+    let (x, y) = (x as i32, y as i32);
+    scene.areas().rev().filter_map(move |(handle, area)| {
+        let resolved = &area.resolved;
+        if !resolved.enabled || !area.area.pickable || resolved.clipped(center_x, center_y) {
+            return None;
+        }
+        let inside = center_x >= resolved.x_min
+            && center_x < resolved.x_max
+            && center_y >= resolved.y_min
+            && center_y < resolved.y_max;
+        let mut text_hit = false;
+        if area.area.text.as_ref().is_some_and(|text| text.pickable) {
+            glyphs.for_each_pixel(area, sprites, time, (x..x + 1, y..y + 1), |_, _, _, _| {
+                text_hit = true
+            });
+        }
+        // The text is drawn on top of the sprite, so it is hit first
+        let frame = match text_hit {
+            true => 0,
+            false if inside => {
+                let sample = sprites.sample(area, time, center_x, center_y)?;
+                if (sample[3] as f32 / 255.0) < sprites.picking(area).threshold() {
+                    return None;
+                }
+                sprites.frame(area, time)?
+            }
+            false => return None,
+        };
+        let hit = Hit {
+            area: handle,
+            local_x: center_x - resolved.x_min,
+            local_y: center_y - resolved.y_min,
+            frame,
+        };
+        Some((hit, area.old_z))
+    })
+}
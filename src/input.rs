@@ -0,0 +1,454 @@
+// Turns mouse input and the cursor picking result into events per area, so hover, press and drag state
+// doesn't have to be tracked on top of currently_hovered_area by every game.
+
+use std::{collections::VecDeque, time::Duration};
+
+use super::{Hit, UiAreaHandle};
+
+pub(crate) const DEFAULT_DOUBLE_CLICK_TIME: Duration = Duration::from_millis(500);
+pub(crate) const DEFAULT_DRAG_THRESHOLD: f32 = 4.0;
+
+/// A mouse button, independent of the windowing library
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    Other(u16),
+}
+
+/// An event on a UiArea, see Stgi::poll_event.
+/// Positions are in the same pixels as the areas, which are virtual pixels with a virtual resolution.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UiEvent {
+    /// The cursor started hovering the area
+    HoverEnter(UiAreaHandle),
+    /// The cursor stopped hovering the area, this always comes before the HoverEnter of the next area
+    HoverLeave(UiAreaHandle),
+    /// A button was pressed while hovering the area. The area captures the button until it is released,
+    /// all following events of the button are sent to it.
+    Press { hit: Hit, button: MouseButton },
+    /// The button that was pressed on the area was released, wherever the cursor is now
+    Release {
+        area: UiAreaHandle,
+        button: MouseButton,
+    },
+    /// The button was pressed and released on the area without dragging, follows the Release
+    Click {
+        area: UiAreaHandle,
+        button: MouseButton,
+    },
+    /// The second click on the same area with the same button within the double click time, follows the Click
+    DoubleClick {
+        area: UiAreaHandle,
+        button: MouseButton,
+    },
+    /// The cursor moved further than the drag threshold while the button is pressed on the area.
+    /// x and y are where the button was pressed.
+    DragStart {
+        area: UiAreaHandle,
+        button: MouseButton,
+        x: f32,
+        y: f32,
+    },
+    /// The cursor moved while dragging the area, delta is the movement since the last DragStart or DragMove
+    DragMove {
+        area: UiAreaHandle,
+        button: MouseButton,
+        x: f32,
+        y: f32,
+        delta_x: f32,
+        delta_y: f32,
+    },
+    /// The button was released while dragging the area, follows the Release. x and y are where it was released,
+    /// Stgi::areas_at finds the drop target there.
+    DragEnd {
+        area: UiAreaHandle,
+        button: MouseButton,
+        x: f32,
+        y: f32,
+    },
+    /// The wheel was scrolled while the cursor hovers the area, the delta is passed on as it was given
+    Wheel {
+        area: UiAreaHandle,
+        delta_x: f32,
+        delta_y: f32,
+    },
+}
+
+/// A button that was pressed on an area and not released yet
+struct Press {
+    area: UiAreaHandle,
+    button: MouseButton,
+    // Where the button was pressed
+    x: f32,
+    y: f32,
+    // Position of the last drag event
+    last_x: f32,
+    last_y: f32,
+    dragging: bool,
+}
+
+pub(crate) struct EventQueue {
+    events: VecDeque<UiEvent>,
+    hovered: Option<UiAreaHandle>,
+    cursor: (f32, f32),
+    presses: Vec<Press>,
+    // (area, button, time) of the last click that did not complete a double click
+    last_click: Option<(UiAreaHandle, MouseButton, Duration)>,
+    double_click_time: Duration,
+    drag_threshold: f32,
+}
+
+impl EventQueue {
+    pub fn new(double_click_time: Duration, drag_threshold: f32) -> Self {
+        Self {
+            events: VecDeque::new(),
+            hovered: None,
+            cursor: (0.0, 0.0),
+            presses: Vec::new(),
+            last_click: None,
+            double_click_time,
+            drag_threshold,
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<UiEvent> {
+        self.events.pop_front()
+    }
+
+    /// Call with every new picking result
+    pub fn hover(&mut self, area: Option<UiAreaHandle>) {
+        if area == self.hovered {
+            return;
+        }
+        if let Some(old) = self.hovered {
+            self.events.push_back(UiEvent::HoverLeave(old));
+        }
+        if let Some(new) = area {
+            self.events.push_back(UiEvent::HoverEnter(new));
+        }
+        self.hovered = area;
+    }
+
+    /// time is the timestamp of the input, see Stgi::set_cursor_pos
+    pub fn cursor_moved(&mut self, x: f32, y: f32, time: Duration) {
+        self.cursor = (x, y);
+        // A click that is too old to complete a double click can be forgotten
+        if self.last_click.is_some_and(|(_, _, last_time)| {
+            time.saturating_sub(last_time) > self.double_click_time
+        }) {
+            self.last_click = None;
+        }
+        for press in &mut self.presses {
+            if !press.dragging {
+                if (x - press.x).hypot(y - press.y) < self.drag_threshold {
+                    continue;
+                }
+                press.dragging = true;
+                self.events.push_back(UiEvent::DragStart {
+                    area: press.area,
+                    button: press.button,
+                    x: press.x,
+                    y: press.y,
+                });
+            }
+            self.events.push_back(UiEvent::DragMove {
+                area: press.area,
+                button: press.button,
+                x,
+                y,
+                delta_x: x - press.last_x,
+                delta_y: y - press.last_y,
+            });
+            press.last_x = x;
+            press.last_y = y;
+        }
+    }
+
+    /// hovered is the area under the cursor right now, time the timestamp of the input.
+    /// Returns the area that was pressed or released, if any.
+    pub fn button(
+        &mut self,
+        button: MouseButton,
+        pressed: bool,
+        hovered: Option<Hit>,
+        time: Duration,
+    ) -> Option<UiAreaHandle> {
+        let index = self.presses.iter().position(|press| press.button == button);
+        if pressed {
+            // Repeated presses without a release in between are ignored
+            let (None, Some(hit)) = (index, hovered) else {
+                return None;
+            };
+            let (x, y) = self.cursor;
+            self.events.push_back(UiEvent::Press { hit, button });
+            self.presses.push(Press {
+                area: hit.area,
+                button,
+                x,
+                y,
+                last_x: x,
+                last_y: y,
+                dragging: false,
+            });
+            return Some(hit.area);
+        }
+
+        let index = index?;
+        let press = self.presses.swap_remove(index);
+        let area = press.area;
+        self.events.push_back(UiEvent::Release { area, button });
+        if press.dragging {
+            let (x, y) = self.cursor;
+            self.events
+                .push_back(UiEvent::DragEnd { area, button, x, y });
+            return Some(area);
+        }
+        if hovered.map(|hit| hit.area) != Some(area) {
+            return Some(area);
+        }
+        self.events.push_back(UiEvent::Click { area, button });
+        match self.last_click {
+            Some((last_area, last_button, last_time))
+                if last_area == area
+                    && last_button == button
+                    && time.saturating_sub(last_time) <= self.double_click_time =>
+            {
+                self.events.push_back(UiEvent::DoubleClick { area, button });
+                self.last_click = None;
+            }
+            _ => self.last_click = Some((area, button, time)),
+        }
+        Some(area)
+    }
+
+    /// Whether any button that was pressed on the area is still held down
+    pub fn is_pressed(&self, area: UiAreaHandle) -> bool {
+        self.presses.iter().any(|press| press.area == area)
+    }
+
+    pub fn wheel(&mut self, delta_x: f32, delta_y: f32) {
+        if let Some(area) = self.hovered {
+            self.events.push_back(UiEvent::Wheel {
+                area,
+                delta_x,
+                delta_y,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::*;
+
+    const BUTTON: MouseButton = MouseButton::Left;
+
+    fn area(id: u32) -> UiAreaHandle {
+        UiAreaHandle {
+            id: NonZeroU32::new(id).unwrap(),
+        }
+    }
+
+    fn hit(id: u32) -> Option<Hit> {
+        Some(Hit {
+            area: area(id),
+            local_x: 0.0,
+            local_y: 0.0,
+            frame: 0,
+        })
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn events(queue: &mut EventQueue) -> Vec<UiEvent> {
+        std::iter::from_fn(|| queue.pop()).collect()
+    }
+
+    fn click(queue: &mut EventQueue, id: u32, time: Duration) {
+        queue.button(BUTTON, true, hit(id), time);
+        queue.button(BUTTON, false, hit(id), time);
+    }
+
+    fn new_queue() -> EventQueue {
+        EventQueue::new(DEFAULT_DOUBLE_CLICK_TIME, DEFAULT_DRAG_THRESHOLD)
+    }
+
+    #[test]
+    fn click_over_press_target() {
+        let mut queue = new_queue();
+        assert_eq!(queue.button(BUTTON, true, hit(1), ms(0)), Some(area(1)));
+        assert!(queue.is_pressed(area(1)));
+        assert_eq!(queue.button(BUTTON, false, hit(1), ms(10)), Some(area(1)));
+        assert!(!queue.is_pressed(area(1)));
+        assert_eq!(
+            events(&mut queue),
+            [
+                UiEvent::Press {
+                    hit: hit(1).unwrap(),
+                    button: BUTTON
+                },
+                UiEvent::Release {
+                    area: area(1),
+                    button: BUTTON
+                },
+                UiEvent::Click {
+                    area: area(1),
+                    button: BUTTON
+                },
+            ]
+        );
+
+        // Released over another area or over none, the press target still gets the release but no click
+        for hovered in [hit(2), None] {
+            queue.button(BUTTON, true, hit(1), ms(1000));
+            assert_eq!(
+                queue.button(BUTTON, false, hovered, ms(1010)),
+                Some(area(1))
+            );
+            assert_eq!(
+                events(&mut queue)[1..],
+                [UiEvent::Release {
+                    area: area(1),
+                    button: BUTTON
+                }]
+            );
+        }
+
+        // Pressing next to all areas does nothing
+        assert_eq!(queue.button(BUTTON, true, None, ms(2000)), None);
+        assert_eq!(queue.button(BUTTON, false, hit(1), ms(2010)), None);
+        assert!(events(&mut queue).is_empty());
+    }
+
+    #[test]
+    fn double_click_window() {
+        let double_click = UiEvent::DoubleClick {
+            area: area(1),
+            button: BUTTON,
+        };
+        let mut queue = new_queue();
+        click(&mut queue, 1, ms(1000));
+        // Exactly at the double click time still counts
+        click(&mut queue, 1, ms(1500));
+        assert_eq!(events(&mut queue).last(), Some(&double_click));
+
+        // A third click starts over
+        click(&mut queue, 1, ms(1600));
+        assert_ne!(events(&mut queue).last(), Some(&double_click));
+
+        // Just too late
+        click(&mut queue, 1, ms(5000));
+        click(&mut queue, 1, ms(5501));
+        assert_ne!(events(&mut queue).last(), Some(&double_click));
+
+        // On another area or with another button
+        click(&mut queue, 1, ms(10000));
+        click(&mut queue, 2, ms(10100));
+        queue.button(MouseButton::Right, true, hit(2), ms(10200));
+        queue.button(MouseButton::Right, false, hit(2), ms(10200));
+        assert!(!events(&mut queue)
+            .iter()
+            .any(|event| matches!(event, UiEvent::DoubleClick { .. })));
+    }
+
+    #[test]
+    fn drag_suppresses_click() {
+        let mut queue = new_queue();
+        queue.cursor_moved(10.0, 10.0, ms(0));
+        queue.button(BUTTON, true, hit(1), ms(0));
+        // Within the threshold
+        queue.cursor_moved(12.0, 12.0, ms(10));
+        assert_eq!(events(&mut queue).len(), 1);
+
+        queue.cursor_moved(14.0, 10.0, ms(20));
+        queue.cursor_moved(20.0, 15.0, ms(30));
+        queue.button(BUTTON, false, hit(1), ms(40));
+        assert_eq!(
+            events(&mut queue),
+            [
+                UiEvent::DragStart {
+                    area: area(1),
+                    button: BUTTON,
+                    x: 10.0,
+                    y: 10.0
+                },
+                UiEvent::DragMove {
+                    area: area(1),
+                    button: BUTTON,
+                    x: 14.0,
+                    y: 10.0,
+                    delta_x: 4.0,
+                    delta_y: 0.0
+                },
+                UiEvent::DragMove {
+                    area: area(1),
+                    button: BUTTON,
+                    x: 20.0,
+                    y: 15.0,
+                    delta_x: 6.0,
+                    delta_y: 5.0
+                },
+                UiEvent::Release {
+                    area: area(1),
+                    button: BUTTON
+                },
+                UiEvent::DragEnd {
+                    area: area(1),
+                    button: BUTTON,
+                    x: 20.0,
+                    y: 15.0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn disabled_areas() {
+        // Stgi passes disabled areas as None, so pressing them does nothing
+        let mut queue = new_queue();
+        assert_eq!(queue.button(BUTTON, true, None, ms(0)), None);
+        assert!(events(&mut queue).is_empty());
+
+        // An area that is disabled while it is pressed still gets the release, but no click
+        queue.button(BUTTON, true, hit(1), ms(0));
+        assert_eq!(queue.button(BUTTON, false, None, ms(10)), Some(area(1)));
+        assert_eq!(
+            events(&mut queue)[1..],
+            [UiEvent::Release {
+                area: area(1),
+                button: BUTTON
+            }]
+        );
+    }
+
+    #[test]
+    fn hover_and_wheel() {
+        let mut queue = new_queue();
+        queue.wheel(0.0, 1.0);
+        queue.hover(Some(area(1)));
+        queue.hover(Some(area(1)));
+        queue.wheel(0.0, -1.0);
+        queue.hover(Some(area(2)));
+        queue.hover(None);
+        assert_eq!(
+            events(&mut queue),
+            [
+                UiEvent::HoverEnter(area(1)),
+                UiEvent::Wheel {
+                    area: area(1),
+                    delta_x: 0.0,
+                    delta_y: -1.0
+                },
+                UiEvent::HoverLeave(area(1)),
+                UiEvent::HoverEnter(area(2)),
+                UiEvent::HoverLeave(area(2)),
+            ]
+        );
+    }
+}
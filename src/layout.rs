@@ -0,0 +1,192 @@
+// Instead of fixed pixel coordinates, areas can be placed relative to an anchor point of their parent
+// (or the window if there is none). Areas with a placement are laid out again whenever the window is resized.
+
+/// A length in pixels or in percent of the parent's size (the window's size if there is no parent)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Px(f32),
+    /// 100.0 is the full width or height of the parent
+    Percent(f32),
+}
+
+impl Default for Unit {
+    fn default() -> Self {
+        Unit::Px(0.0)
+    }
+}
+
+impl Unit {
+    fn resolve(self, parent_size: f32) -> f32 {
+        match self {
+            Unit::Px(px) => px,
+            Unit::Percent(percent) => percent / 100.0 * parent_size,
+        }
+    }
+}
+
+/// The point of the parent an area is aligned to. The same point of the area itself is put there,
+/// e.g. BottomRight puts the bottom right corner of the area into the bottom right corner of the parent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Anchor {
+    #[default]
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    /// Relative position of the anchor point, (0, 0) is the top left and (1, 1) the bottom right corner
+    fn factors(self) -> (f32, f32) {
+        match self {
+            Anchor::TopLeft => (0.0, 0.0),
+            Anchor::Top => (0.5, 0.0),
+            Anchor::TopRight => (1.0, 0.0),
+            Anchor::Left => (0.0, 0.5),
+            Anchor::Center => (0.5, 0.5),
+            Anchor::Right => (1.0, 0.5),
+            Anchor::BottomLeft => (0.0, 1.0),
+            Anchor::Bottom => (0.5, 1.0),
+            Anchor::BottomRight => (1.0, 1.0),
+        }
+    }
+}
+
+/// Position and size of an area relative to an anchor point.
+/// Positive offsets move the area to the right and down, no matter which anchor is used.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Placement {
+    pub anchor: Anchor,
+    pub offset_x: Unit,
+    pub offset_y: Unit,
+    pub width: Unit,
+    pub height: Unit,
+}
+
+impl Placement {
+    /// (x_min, x_max, y_min, y_max) relative to the top left corner of the parent
+    pub(crate) fn resolve(&self, parent_width: f32, parent_height: f32) -> (f32, f32, f32, f32) {
+        let width = self.width.resolve(parent_width);
+        let height = self.height.resolve(parent_height);
+        let (anchor_x, anchor_y) = self.anchor.factors();
+        let x_min = anchor_x * (parent_width - width) + self.offset_x.resolve(parent_width);
+        let y_min = anchor_y * (parent_height - height) + self.offset_y.resolve(parent_height);
+        (x_min, x_min + width, y_min, y_min + height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{scene::Scene, UiArea};
+
+    fn placement(anchor: Anchor) -> Placement {
+        Placement {
+            anchor,
+            offset_x: Unit::Px(1.0),
+            offset_y: Unit::Px(2.0),
+            width: Unit::Px(10.0),
+            height: Unit::Px(20.0),
+        }
+    }
+
+    #[test]
+    fn anchors() {
+        for (anchor, x_min, y_min) in [
+            (Anchor::TopLeft, 1.0, 2.0),
+            (Anchor::Top, 46.0, 2.0),
+            (Anchor::TopRight, 91.0, 2.0),
+            (Anchor::Left, 1.0, 42.0),
+            (Anchor::Center, 46.0, 42.0),
+            (Anchor::Right, 91.0, 42.0),
+            (Anchor::BottomLeft, 1.0, 82.0),
+            (Anchor::Bottom, 46.0, 82.0),
+            (Anchor::BottomRight, 91.0, 82.0),
+        ] {
+            assert_eq!(
+                placement(anchor).resolve(100.0, 100.0),
+                (x_min, x_min + 10.0, y_min, y_min + 20.0),
+                "{anchor:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn units() {
+        let px = Unit::Px(20.0);
+        let percent = Unit::Percent(10.0);
+        // Percentages of the width on the x axis and of the height on the y axis
+        for (offset, size, x_min, x_max, y_min, y_max) in [
+            (px, px, 20.0, 40.0, 20.0, 40.0),
+            (px, percent, 20.0, 40.0, 20.0, 25.0),
+            (percent, px, 20.0, 40.0, 5.0, 25.0),
+            (percent, percent, 20.0, 40.0, 5.0, 10.0),
+        ] {
+            let placement = Placement {
+                anchor: Anchor::TopLeft,
+                offset_x: offset,
+                offset_y: offset,
+                width: px,
+                height: size,
+            };
+            assert_eq!(
+                placement.resolve(200.0, 50.0),
+                (x_min, x_max, y_min, y_max),
+                "offset {offset:?}, size {size:?}"
+            );
+        }
+        let placement = Placement {
+            anchor: Anchor::BottomRight,
+            offset_x: Unit::Percent(-5.0),
+            offset_y: Unit::Px(-5.0),
+            width: Unit::Percent(50.0),
+            height: Unit::Percent(100.0),
+        };
+        assert_eq!(placement.resolve(200.0, 50.0), (90.0, 190.0, -5.0, 45.0));
+    }
+
+    #[test]
+    fn relaid_out_on_resize() {
+        let mut scene: Scene<u32, u32> = Scene::new(100.0, 50.0);
+        let parent = scene.add_area(UiArea {
+            placement: Some(Placement {
+                anchor: Anchor::BottomRight,
+                width: Unit::Percent(50.0),
+                height: Unit::Px(20.0),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let child = scene.add_area(UiArea {
+            placement: Some(Placement {
+                anchor: Anchor::Center,
+                width: Unit::Percent(50.0),
+                height: Unit::Percent(50.0),
+                ..Default::default()
+            }),
+            parent: Some(parent),
+            ..Default::default()
+        });
+        let fixed = scene.add_area(UiArea {
+            x_min: 1.0,
+            x_max: 2.0,
+            y_min: 3.0,
+            y_max: 4.0,
+            ..Default::default()
+        });
+        scene.update();
+        assert_eq!(scene.area_rect(parent), Some((50.0, 100.0, 30.0, 50.0)));
+        assert_eq!(scene.area_rect(child), Some((62.5, 87.5, 35.0, 45.0)));
+
+        scene.resize(200.0, 100.0);
+        scene.update();
+        assert_eq!(scene.area_rect(parent), Some((100.0, 200.0, 80.0, 100.0)));
+        assert_eq!(scene.area_rect(child), Some((125.0, 175.0, 85.0, 95.0)));
+        assert_eq!(scene.area_rect(fixed), Some((1.0, 2.0, 3.0, 4.0)));
+    }
+}
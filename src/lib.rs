@@ -1,39 +1,52 @@
 use std::hash::Hash;
+use std::ops::Range;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::Arc;
+use std::time::Duration;
 use std::{fmt::Debug, num::NonZeroU32};
 
 use ahash::HashMap;
+use atlas::SpriteAtlas;
 use builder::StgiBuilder;
 use bytemuck::{Pod, Zeroable};
-use text::{FontId, TextRenderer};
+use canvas::VirtualCanvas;
+use cpu::{CpuGlyphs, CpuSprites};
+use image::{Rgba, RgbaImage};
+use input::{EventQueue, MouseButton, UiEvent};
+use layout::Placement;
+use scene::{InternalUiArea, Scene};
+use sprite::{Picking, Sprite};
+use text::{FontId, HorizontalAlign, Padding, TextEffect, TextRenderer, VerticalAlign, Wrap};
 use util::{BufferInitDescriptor, DeviceExt};
 use wgpu::*;
 
+mod atlas;
 pub mod builder;
+mod canvas;
+mod cpu;
+pub mod input;
+pub mod layout;
+pub mod markup;
+mod scene;
+pub mod software;
+pub mod sprite;
 pub mod text;
 
 pub trait SpriteId: Clone + Eq + Debug + Hash {}
 impl<T> SpriteId for T where T: Clone + Eq + Debug + Hash {}
 
-/// The order in which the areas are rendered, meaning: Fourth will be rendered on top of Third, etc.
-#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Default)]
-pub enum ZOrder {
-    First,
-    Second,
-    #[default]
-    Third,
-    Fourth,
+/// The order in which the areas are rendered and picked, meaning: a higher layer will be rendered on top of a lower one.
+/// Inside a layer, the area with the higher key is rendered on top. If both are equal, the area added last is on top.
+/// The text of an area is rendered right after its sprite, so it follows the same order.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord, Default)]
+pub struct ZOrder {
+    pub layer: i32,
+    pub key: i32,
 }
 
 impl ZOrder {
-    fn to_usize(&self) -> usize {
-        match self {
-            ZOrder::First => 0,
-            ZOrder::Second => 1,
-            ZOrder::Third => 2,
-            ZOrder::Fourth => 3,
-        }
+    pub const fn new(layer: i32, key: i32) -> Self {
+        Self { layer, key }
     }
 }
 
@@ -43,7 +56,20 @@ pub struct UiAreaHandle {
     id: NonZeroU32,
 }
 
+/// A picked area together with the position inside of it that was picked
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hit {
+    pub area: UiAreaHandle,
+    /// Position in pixels relative to the top left corner of the area, in virtual pixels with a virtual resolution
+    pub local_x: f32,
+    pub local_y: f32,
+    /// The animation frame of the sprite that was hit, 0 if the text of the area was hit
+    pub frame: u32,
+}
+
 /// A UiArea is a rectangular area on the screen that can be rendered with a sprite and/or text.
+/// Positions are in pixels, relative to the top left corner of the parent area or the window if there is none.
+/// Instead of fixed positions, a placement can be used, see layout::Placement.
 #[derive(Debug, Clone)]
 pub struct UiArea<S: SpriteId, F: FontId> {
     pub x_min: f32,
@@ -52,22 +78,268 @@ pub struct UiArea<S: SpriteId, F: FontId> {
     pub y_max: f32,
     pub z: ZOrder,
     pub sprite: Option<S>,
+    /// A disabled area is neither rendered nor picked, neither are its children
     pub enabled: bool,
-    pub text: Option<Text<F>>,
+    pub text: Option<Text<S, F>>,
+    /// RGBA color the sprite is multiplied with. The alpha channel acts as opacity of the sprite and the text
+    /// and is inherited by the children.
+    /// Only affects rendering, cursor picking still uses the alpha of the sprite itself.
+    pub color: [f32; 4],
+    pub animation: AreaAnimation,
+    /// Everything of the area outside of this rect is cut off, in rendering as well as in cursor picking.
+    /// Also applies to the text of the area and to all children. Positioned like the area itself.
+    pub clip: Option<ClipRect>,
+    /// Children move with their parent and are removed together with it. The z order is not inherited.
+    /// A parent that does not exist (anymore) is ignored, so is the area itself or one of its (grand)children,
+    /// which would make a cycle. Such a parent is checked again the next time the area is changed.
+    pub parent: Option<UiAreaHandle>,
+    /// If set, the area is laid out relative to its parent (or the window) and x_min, x_max, y_min, y_max are ignored.
+    /// Areas with a placement are laid out again whenever the window is resized.
+    pub placement: Option<Placement>,
+    /// Overrides the picking of the sprite, e.g. to pick faint click targets or to ignore soft glows.
+    /// Text is always picked wherever its glyphs, its inline sprites, its outline or its shadow cover the pixel.
+    pub picking: Option<Picking>,
+    /// A non-pickable area is still rendered, but the cursor picks the areas below it, e.g. for decorative overlays.
+    /// Applies to the sprite and the text of the area, but not to its children.
+    pub pickable: bool,
+    /// Sprites shown instead of sprite while the area is hovered, pressed, disabled or focused
+    pub states: StateSprites<S>,
+    /// Unlike an area that is not enabled, a disabled area is still rendered and picked, with the disabled sprite.
+    /// It can not be pressed, focused or scrolled, but still gets hover events, e.g. for tooltips.
+    pub disabled: bool,
+}
+
+impl<S: SpriteId, F: FontId> Default for UiArea<S, F> {
+    fn default() -> Self {
+        Self {
+            x_min: 0.0,
+            x_max: 0.0,
+            y_min: 0.0,
+            y_max: 0.0,
+            z: ZOrder::default(),
+            sprite: None,
+            enabled: true,
+            text: None,
+            color: [1.0, 1.0, 1.0, 1.0],
+            animation: AreaAnimation::default(),
+            clip: None,
+            parent: None,
+            placement: None,
+            picking: None,
+            pickable: true,
+            states: StateSprites::default(),
+            disabled: false,
+        }
+    }
+}
+
+/// Sprites Stgi switches to on its own, based on the cursor picking and the mouse input.
+/// A state without a sprite shows the sprite of the next one: disabled, pressed, hovered, focused and
+/// finally the sprite of the area. A disabled area only ever shows the disabled sprite or its own sprite.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateSprites<S: SpriteId> {
+    /// While the cursor is over the area
+    pub hovered: Option<S>,
+    /// While a mouse button that was pressed on the area is held down
+    pub pressed: Option<S>,
+    /// While UiArea::disabled is set
+    pub disabled: Option<S>,
+    /// While the area has the focus, see Stgi::set_focus
+    pub focused: Option<S>,
+}
+
+impl<S: SpriteId> Default for StateSprites<S> {
+    fn default() -> Self {
+        Self {
+            hovered: None,
+            pressed: None,
+            disabled: None,
+            focused: None,
+        }
+    }
+}
+
+/// A rectangle in pixels used to cut off areas, e.g. the frame of a scrolling list
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ClipRect {
+    pub x_min: f32,
+    pub x_max: f32,
+    pub y_min: f32,
+    pub y_max: f32,
+}
+
+impl ClipRect {
+    fn offset(self, x: f32, y: f32) -> Self {
+        Self {
+            x_min: self.x_min + x,
+            x_max: self.x_max + x,
+            y_min: self.y_min + y,
+            y_max: self.y_max + y,
+        }
+    }
+
+    /// Might be empty, meaning everything is cut off
+    fn intersection(self, other: Self) -> Self {
+        Self {
+            x_min: self.x_min.max(other.x_min),
+            x_max: self.x_max.min(other.x_max),
+            y_min: self.y_min.max(other.y_min),
+            y_max: self.y_max.min(other.y_max),
+        }
+    }
+
+    /// (x_min, x_max, y_min, y_max) as they are stored in the instance and glyph data
+    fn to_instance_data(clip: Option<Self>) -> [f32; 4] {
+        match clip {
+            Some(clip) => [clip.x_min, clip.x_max, clip.y_min, clip.y_max],
+            None => [f32::MIN, f32::MAX, f32::MIN, f32::MAX],
+        }
+    }
+}
+
+/// Animation state of a single UiArea. Times are measured on the animation clock, see Stgi::animation_time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaAnimation {
+    /// Plays the animation as if it started at the given time
+    Playing { start: Duration },
+    /// Frozen after the given amount of time has elapsed
+    Paused { elapsed: Duration },
+    /// Always shows the given frame
+    Frame(u32),
+}
+
+impl Default for AreaAnimation {
+    fn default() -> Self {
+        AreaAnimation::Playing {
+            start: Duration::ZERO,
+        }
+    }
+}
+
+impl AreaAnimation {
+    /// Mode and value as they are stored in the instance data
+    fn to_instance_data(self) -> (u32, u32) {
+        match self {
+            AreaAnimation::Playing { start } => (0, start.as_millis() as u32),
+            AreaAnimation::Paused { elapsed } => (1, elapsed.as_millis() as u32),
+            AreaAnimation::Frame(frame) => (2, frame),
+        }
+    }
 }
 
-/// Text inside a UiArea
+/// Text inside a UiArea. Font, size, color, underline and effect are the style of text, spans can follow it
+/// with styles of their own or with inline sprites.
 #[derive(Debug, Clone)]
-pub struct Text<F: FontId> {
+pub struct Text<S: SpriteId, F: FontId> {
     pub font: F,
     pub size: u16,
     pub text: String,
+    /// Non-pickable text is still rendered, but only the sprite of the area is picked
+    pub pickable: bool,
+    /// RGBA color of the glyphs, white by default. The color of the area does not tint the text,
+    /// but its opacity and the one of its parents is applied to the glyphs, the outline and the shadow.
+    pub color: [f32; 4],
+    pub underline: bool,
+    pub effect: TextEffect,
+    /// Laid out right after text as one paragraph, e.g. to highlight a number in a tooltip
+    pub spans: Vec<TextSpan<S, F>>,
+    /// Drawn around the glyphs and underlines, below them
+    pub outline: Option<TextOutline>,
+    /// Drawn below the glyphs and their outline, in the shape of both
+    pub shadow: Option<TextShadow>,
+    /// Centered by default
+    pub horizontal_align: HorizontalAlign,
+    /// Centered by default
+    pub vertical_align: VerticalAlign,
+    /// Height of a line as a multiple of the line height of the font, 1.0 by default
+    pub line_height: f32,
+    pub wrap: Wrap,
+    /// Extra space between two letters in pixels, may be negative. Lines are still wrapped as if it was 0.
+    pub letter_spacing: f32,
+    /// Space between the border of the area and the text, the text is aligned and wrapped inside of it
+    pub padding: Padding,
 }
 
-struct InternalUiArea<S: SpriteId, F: FontId> {
-    old_z: ZOrder,
-    instances_index: Option<u32>,
-    area: UiArea<S, F>,
+impl<S: SpriteId, F: FontId> Text<S, F> {
+    /// Pickable white text with the given font and size, centered in the area and wrapped between words
+    pub fn new(font: F, size: u16, text: impl Into<String>) -> Self {
+        Self {
+            font,
+            size,
+            text: text.into(),
+            pickable: true,
+            color: [1.0, 1.0, 1.0, 1.0],
+            underline: false,
+            effect: TextEffect::None,
+            spans: Vec::new(),
+            outline: None,
+            shadow: None,
+            horizontal_align: HorizontalAlign::default(),
+            vertical_align: VerticalAlign::default(),
+            line_height: 1.0,
+            wrap: Wrap::default(),
+            letter_spacing: 0.0,
+            padding: Padding::default(),
+        }
+    }
+}
+
+/// A part of a Text with its own style. Outline, shadow and layout are the ones of the Text.
+#[derive(Debug, Clone)]
+pub struct TextSpan<S: SpriteId, F: FontId> {
+    pub text: String,
+    pub font: F,
+    pub size: u16,
+    /// The color of the glyphs, an inline sprite is multiplied with it
+    pub color: [f32; 4],
+    pub underline: bool,
+    pub effect: TextEffect,
+    /// Shown inline instead of the text, e.g. for button prompts or currency icons. The sprite is scaled to the
+    /// height of a line of the font at the size of the span and sits on the baseline like the glyphs.
+    /// It is drawn and picked like the glyphs, but the outline and the shadow of the text leave it out.
+    /// Animated sprites play on the animation clock.
+    pub sprite: Option<S>,
+}
+
+impl<S: SpriteId, F: FontId> TextSpan<S, F> {
+    /// White text without underline or effect
+    pub fn new(font: F, size: u16, text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            font,
+            size,
+            color: [1.0, 1.0, 1.0, 1.0],
+            underline: false,
+            effect: TextEffect::None,
+            sprite: None,
+        }
+    }
+
+    /// An inline sprite as high as a line of the given font and size
+    pub fn sprite(font: F, size: u16, sprite: S) -> Self {
+        Self {
+            sprite: Some(sprite),
+            ..Self::new(font, size, "")
+        }
+    }
+}
+
+/// Outline around every glyph of a Text. Where the outlines of neighboring glyphs overlap,
+/// a partly transparent outline is blended twice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextOutline {
+    pub color: [f32; 4],
+    /// Thickness in pixels, covers diagonal neighbors as well
+    pub width: u16,
+}
+
+/// Drop shadow of a Text
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextShadow {
+    pub color: [f32; 4],
+    /// Offset from the glyphs in pixels, positive values move the shadow to the bottom right
+    pub offset_x: f32,
+    pub offset_y: f32,
 }
 
 /// Only for a small vertex buffer, rendering is done with instances
@@ -98,10 +370,18 @@ struct Instance {
     y_min: f32,
     y_max: f32,
     area_id: u32,
+    color: [f32; 4],
+    // 0: playing since animation_value, 1: paused after animation_value, 2: fixed frame animation_value
+    animation_mode: u32,
+    animation_value: u32,
+    // x_min, x_max, y_min, y_max
+    clip: [f32; 4],
+    // Pixels with a lower alpha are not picked
+    pick_threshold: f32,
 }
 
 impl Instance {
-    const ATTRIBS: [VertexAttribute; 6] = vertex_attr_array![1 => Uint32, 2 => Float32, 3 => Float32, 4 => Float32, 5 => Float32, 6 => Uint32];
+    const ATTRIBS: [VertexAttribute; 11] = vertex_attr_array![1 => Uint32, 2 => Float32, 3 => Float32, 4 => Float32, 5 => Float32, 6 => Uint32, 7 => Float32x4, 8 => Uint32, 9 => Uint32, 10 => Float32x4, 11 => Float32];
     fn desc() -> VertexBufferLayout<'static> {
         use std::mem;
         VertexBufferLayout {
@@ -115,11 +395,34 @@ impl Instance {
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
 struct UniformData {
-    current_frame: u32,
+    // Animation clock in milliseconds
+    time: u32,
     window_width: f32,
     window_height: f32,
 }
 
+/// Per sprite data, these reside in the offset table
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct SpriteInfo {
+    // Index of the first frame in the allocation table
+    offset: u32,
+    // Amount of frames
+    size: u32,
+    // Size of a single frame in pixels
+    width: f32,
+    height: f32,
+    // Nine-slice insets in pixels, all zero for regular sprites
+    slice_left: f32,
+    slice_right: f32,
+    slice_top: f32,
+    slice_bottom: f32,
+    slice_mode: u32,
+    // Duration of a single frame in milliseconds
+    frame_duration: u32,
+    playback: u32,
+}
+
 /// A single allocation in the atlas, these reside in the allocation table
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
@@ -133,42 +436,94 @@ struct Allocation {
 
 struct InstanceBuffer {
     staging: Vec<Instance>,
-    order: Vec<UiAreaHandle>,
     buffer: Buffer,
+    // In instances
     capacity: u32,
-    size: u32,
+}
+
+/// A single draw call, sprites and text are interleaved to keep the draw order of the areas
+#[derive(Debug, Clone, PartialEq)]
+enum DrawCommand {
+    // Range of instances in the instance buffer
+    Sprites(Range<u32>),
+    // Range of glyphs in the text vertex buffer
+    Text(Range<u32>),
+}
+
+impl DrawCommand {
+    /// Appends the command, merging it into the last one if it continues its range
+    fn push(commands: &mut Vec<DrawCommand>, command: DrawCommand) {
+        match (commands.last_mut(), command) {
+            (Some(DrawCommand::Sprites(previous)), DrawCommand::Sprites(next))
+            | (Some(DrawCommand::Text(previous)), DrawCommand::Text(next))
+                if previous.end == next.start =>
+            {
+                previous.end = next.end;
+            }
+            (_, command) => commands.push(command),
+        }
+    }
+}
+
+/// Where an area ended up in the buffers and draw commands in the last rebuild of the draw commands
+#[derive(Debug, Clone, PartialEq)]
+struct DrawnArea {
+    // Index in the instance buffer
+    instance: Option<u32>,
+    // Range in the text vertex buffer, None without visible text
+    glyphs: Option<Range<u32>>,
+    // Whether the sprite and the text are in the picking commands
+    picked: (bool, bool),
+}
+
+impl DrawnArea {
+    fn picked<S: SpriteId, F: FontId>(area: &InternalUiArea<S, F>) -> (bool, bool) {
+        let text_pickable = area.area.text.as_ref().is_some_and(|text| text.pickable);
+        (area.area.pickable, area.area.pickable && text_pickable)
+    }
+}
+
+/// Amount of picking results that can be read back at the same time
+const CURSOR_PICKING_SLOTS: usize = 3;
+
+/// A staging buffer the picking result of a frame is copied into, several of them can be in flight at once
+struct CursorPickingSlot {
+    buffer: Arc<Buffer>,
+    // Mapped or waiting to be mapped, so it must not be copied into
+    in_flight: bool,
 }
 
 /// The main struct for the library, this is where all the magic happens.
 pub struct Stgi<S: SpriteId, F: FontId> {
     text_renderer: TextRenderer<F>,
 
-    sprite_indices: HashMap<S, u32>,
-    offset_table: Buffer,
-    allocation_table: Buffer,
-    atlas_texture: Texture,
-    atlas_view: TextureView,
-    atlas_sampler: Sampler,
-    atlas_bind_group: BindGroup,
+    atlas: SpriteAtlas<S>,
+    // CPU copies for hit_test
+    cpu_sprites: CpuSprites<S>,
+    cpu_glyphs: CpuGlyphs<F>,
 
     index_buffer: Buffer,
     index_buffer_size: u32,
     vertex_buffer: Buffer,
-    // Ordered by z-index
-    instance_buffers: Vec<Option<InstanceBuffer>>,
+    // Ordered like draw_order
+    instance_buffer: InstanceBuffer,
+    draw_commands: Vec<DrawCommand>,
+    // Like draw_commands, but without the areas and texts that are not pickable
+    picking_commands: Vec<DrawCommand>,
+    // Every area as of the last rebuild of the draw commands
+    drawn_areas: HashMap<UiAreaHandle, DrawnArea>,
     render_pipeline: RenderPipeline,
 
     uniform_data: UniformData,
     uniform_buffer: Buffer,
     uniform_bind_group: BindGroup,
 
-    next_area_id: NonZeroU32,
-    ui_areas: HashMap<UiAreaHandle, InternalUiArea<S, F>>,
-    dirty_areas: Vec<UiAreaHandle>,
-    areas_to_remove: Vec<UiAreaHandle>,
-    recently_cleared: bool,
+    scene: Scene<S, F>,
 
-    animation_frame: u32,
+    // Only with a virtual resolution
+    canvas: Option<VirtualCanvas>,
+    // Format of the surface, all render pipelines use it
+    format: TextureFormat,
 
     // Cursor picking
     cursor_picking_texture: Texture,
@@ -178,89 +533,67 @@ pub struct Stgi<S: SpriteId, F: FontId> {
     cursor_moved: bool,
     cursor_pos_uniform: [u32; 2],
     cursor_pos_uniform_buffer: Buffer,
-    cursor_picking_result_staging_buffer: Arc<Buffer>,
+    cursor_picking_slots: Vec<CursorPickingSlot>,
+    // The slot the last render copied the result into, mapped in post_render_work
+    cursor_picking_slot: Option<usize>,
+    // Counts the results read back, so an older result never replaces a newer one
+    cursor_picking_frame: u64,
+    cursor_picking_result_frame: u64,
     cursor_picking_result_storage_buffer: Buffer,
     cursor_picking_compute_bind_group_layout: BindGroupLayout,
     cursor_picking_compute_bind_group: BindGroup,
-    cursor_picking_result_sender: Sender<u32>,
-    cursor_picking_result_receiver: Receiver<u32>,
-    cursor_picking_result: Option<UiAreaHandle>,
+    // (slot, frame, result), the result is None if mapping failed
+    cursor_picking_result_sender: Sender<(usize, u64, Option<[u32; 4]>)>,
+    cursor_picking_result_receiver: Receiver<(usize, u64, Option<[u32; 4]>)>,
+    cursor_picking_result: Option<Hit>,
+    events: EventQueue,
 }
 
 impl<S: SpriteId, F: FontId> Stgi<S, F> {
-    /// All sprites and fonts must be registered before creating a STGI instance, for performance reasons.
+    /// All fonts must be registered before creating a STGI instance, for performance reasons.
     /// That's why there is a builder pattern to create a STGI instance.
+    /// Sprites can be registered in the builder or later on with add_sprite.
     pub fn builder() -> StgiBuilder<S, F> {
         StgiBuilder::new()
     }
 
     /// Adds a new UIArea to the STGI instance. To edit the area later, use the returned handle and
     pub fn add_area(&mut self, area: UiArea<S, F>) -> UiAreaHandle {
-        let handle = UiAreaHandle {
-            id: self.next_area_id,
-        };
-        self.next_area_id = self.next_area_id.checked_add(1).unwrap();
-        self.ui_areas.insert(
-            handle,
-            InternalUiArea {
-                old_z: area.z,
-                instances_index: None,
-                area,
-            },
-        );
-        match self.dirty_areas.binary_search(&handle) {
-            Ok(_) => {}
-            Err(index) => {
-                self.dirty_areas.insert(index, handle);
-            }
-        }
-        handle
+        self.scene.add_area(area)
     }
 
     /// Gets a reference to a UiArea by its handle
     pub fn area(&self, handle: UiAreaHandle) -> Option<&UiArea<S, F>> {
-        self.ui_areas.get(&handle).map(|area| &area.area)
+        self.scene.area(handle)
     }
 
+    /// Removes the area in the next update, together with all of its children and their children.
+    /// The handles of all removed areas become invalid, to keep the children move them to another parent first.
     pub fn remove_area(&mut self, area: UiAreaHandle) {
-        match self.areas_to_remove.binary_search(&area) {
-            Ok(_) => {}
-            Err(index) => {
-                self.areas_to_remove.insert(index, area);
-            }
-        }
+        self.scene.remove_area(area);
     }
 
     /// Removes all areas from the STGI instance.
     pub fn clear(&mut self) {
-        self.ui_areas.clear();
-        self.dirty_areas.clear();
-        self.areas_to_remove.clear();
-        for buffer in self.instance_buffers.iter_mut() {
-            *buffer = None;
-        }
-        self.recently_cleared = true;
+        self.scene.clear();
     }
 
     /// Gets a mutable reference to a UiArea by its handle.
-    /// This automatically marks the area as dirty, so it will be recalculated in the next frame.
+    /// This automatically marks the area and its children as dirty, so they will be recalculated in the next frame.
     pub fn area_mut(&mut self, handle: UiAreaHandle) -> Option<&mut UiArea<S, F>> {
-        if let Some(area) = self.ui_areas.get_mut(&handle) {
-            match self.dirty_areas.binary_search(&handle) {
-                Ok(_) => {}
-                Err(index) => {
-                    self.dirty_areas.insert(index, handle);
-                }
-            }
-            return Some(&mut area.area);
-        }
-        None
+        self.scene.area_mut(handle)
     }
 
-    /// Advances all sprite animations by one frame.
-    pub fn next_animation_frame(&mut self, queue: &Queue) {
-        self.animation_frame += 1;
-        self.uniform_data.current_frame = self.animation_frame;
+    /// Gets the children of a UiArea, as of the last update
+    pub fn children(&self, handle: UiAreaHandle) -> &[UiAreaHandle] {
+        self.scene.children(handle)
+    }
+
+    /// Advances the animation clock, every animated sprite picks its frame based on this clock
+    /// and the frame duration it was registered with.
+    pub fn advance_animations(&mut self, queue: &Queue, delta: Duration) {
+        self.scene.advance_animations(delta);
+        self.uniform_data.time = self.scene.animation_time().as_millis() as u32;
         queue.write_buffer(
             &self.uniform_buffer,
             0,
@@ -268,17 +601,212 @@ impl<S: SpriteId, F: FontId> Stgi<S, F> {
         );
     }
 
+    /// Advances the animation clock by sprite::DEFAULT_FRAME_DURATION, which shows the next frame of every
+    /// animated sprite that was added without a frame duration.
+    #[deprecated(note = "animations are driven by time, use advance_animations")]
+    pub fn next_animation_frame(&mut self, queue: &Queue) {
+        self.advance_animations(queue, sprite::DEFAULT_FRAME_DURATION);
+    }
+
+    /// The current time of the animation clock
+    pub fn animation_time(&self) -> Duration {
+        self.scene.animation_time()
+    }
+
+    /// Restarts the animation of an area from its first frame.
+    pub fn restart_animation(&mut self, handle: UiAreaHandle) {
+        self.scene.restart_animation(handle);
+    }
+
+    /// Freezes the animation of an area at its current frame.
+    pub fn pause_animation(&mut self, handle: UiAreaHandle) {
+        self.scene.pause_animation(handle);
+    }
+
+    /// Continues a paused animation from where it was paused.
+    pub fn resume_animation(&mut self, handle: UiAreaHandle) {
+        self.scene.resume_animation(handle);
+    }
+
+    /// Shows a fixed frame of the animation of an area, until the animation is restarted.
+    pub fn seek_animation(&mut self, handle: UiAreaHandle, frame: u32) {
+        self.scene.seek_animation(handle, frame);
+    }
+
+    /// Registers a new sprite at runtime, areas already referencing the sprite id will show it.
+    /// Panics if the sprite is already registered, use replace_sprite instead.
+    pub fn add_sprite(&mut self, device: &Device, queue: &Queue, sprite_id: S, sprite: Sprite) {
+        assert!(
+            !self.atlas.contains(&sprite_id),
+            "Sprite: {:?} already registered",
+            sprite_id
+        );
+        sprite.validate();
+        self.atlas.insert(sprite_id.clone(), &sprite);
+        self.atlas.flush(device, queue);
+        self.cpu_sprites.insert(sprite_id.clone(), &sprite);
+        self.scene.mark_sprite_dirty(&sprite_id);
+    }
+
+    /// Replaces an already registered sprite, all areas using it are updated.
+    /// Panics if the sprite is not registered.
+    pub fn replace_sprite(&mut self, device: &Device, queue: &Queue, sprite_id: S, sprite: Sprite) {
+        sprite.validate();
+        assert!(
+            self.atlas.remove(&sprite_id),
+            "Sprite: {:?} not registered",
+            sprite_id
+        );
+        self.atlas.insert(sprite_id.clone(), &sprite);
+        self.atlas.flush(device, queue);
+        self.cpu_sprites.insert(sprite_id.clone(), &sprite);
+        self.scene.mark_sprite_dirty(&sprite_id);
+    }
+
+    /// Removes a sprite and frees its atlas space. Areas using it are rendered without a sprite until
+    /// a sprite with the same id is added again. Returns false if the sprite was not registered.
+    pub fn remove_sprite(&mut self, device: &Device, queue: &Queue, sprite_id: &S) -> bool {
+        if !self.atlas.remove(sprite_id) {
+            return false;
+        }
+        self.atlas.flush(device, queue);
+        self.cpu_sprites.remove(sprite_id);
+        self.scene.mark_sprite_dirty(sprite_id);
+        true
+    }
+
+    /// Whether a sprite with the given id is registered
+    pub fn has_sprite(&self, sprite_id: &S) -> bool {
+        self.atlas.contains(sprite_id)
+    }
+
     /// Updates the cursor position used for cursor picking. Call this when the mouse cursor moves.
-    pub fn set_cursor_pos(&mut self, x: u32, y: u32) {
-        self.cursor_pos_uniform = [x, y];
+    /// The position is in window pixels, with a virtual resolution it is mapped onto the virtual canvas.
+    /// time is when the input happened, measured from any fixed point like the start of the game. It must not go
+    /// backwards, but is independent of the animation clock.
+    pub fn set_cursor_pos(&mut self, x: u32, y: u32, time: Duration) {
+        // Nothing can be hovered in the letterbox
+        self.cursor_pos_uniform = self
+            .to_canvas(x, y)
+            .map_or([u32::MAX, u32::MAX], |(x, y)| [x, y]);
         self.cursor_moved = true;
+        let (x, y) = match &self.canvas {
+            Some(canvas) => canvas.project(x, y),
+            None => (x as f32, y as f32),
+        };
+        self.events.cursor_moved(x, y, time);
+    }
+
+    /// Call this when a mouse button is pressed or released. A press goes to the area under the cursor,
+    /// which then captures the button until it is released, see input::UiEvent. It also gets the focus,
+    /// a press next to all areas takes the focus away.
+    /// The area is picked right away like hit_test does, so it doesn't lag behind the cursor like
+    /// currently_hovered_area. time is when the input happened like for set_cursor_pos, double clicks are timed
+    /// with it.
+    pub fn set_mouse_button(&mut self, button: MouseButton, pressed: bool, time: Duration) {
+        let hovered = self
+            .hit_at_cursor()
+            .filter(|hit| !self.is_disabled(hit.area));
+        let area = self.events.button(button, pressed, hovered, time);
+        if let Some(area) = area {
+            self.scene.set_pressed(area, self.events.is_pressed(area));
+        }
+        if pressed && (area.is_some() || hovered.is_none()) {
+            self.scene.set_focused(area);
+        }
+    }
+
+    /// Call this when the mouse wheel is scrolled, the currently hovered area gets a Wheel event
+    pub fn scroll_wheel(&mut self, delta_x: f32, delta_y: f32) {
+        if self
+            .currently_hovered_area()
+            .is_some_and(|area| self.is_disabled(area))
+        {
+            return;
+        }
+        self.events.wheel(delta_x, delta_y);
+    }
+
+    /// The area under the cursor as of the last update, picked on the CPU
+    fn hit_at_cursor(&self) -> Option<Hit> {
+        let [x, y] = self.cursor_pos_uniform;
+        // The cursor is in the letterbox
+        if x == u32::MAX {
+            return None;
+        }
+        cpu::areas_at(&self.scene, &self.cpu_sprites, &self.cpu_glyphs, x, y)
+            .next()
+            .map(|(hit, _)| hit)
+    }
+
+    fn is_disabled(&self, handle: UiAreaHandle) -> bool {
+        self.scene.area(handle).is_some_and(|area| area.disabled)
+    }
+
+    /// The area that has the focus, it shows its focused sprite. Set by pressing an area or with set_focus.
+    pub fn focused(&self) -> Option<UiAreaHandle> {
+        self.scene.focused()
+    }
+
+    /// Gives the focus to an area, e.g. for keyboard navigation. None takes the focus away.
+    pub fn set_focus(&mut self, area: Option<UiAreaHandle>) {
+        self.scene.set_focused(area);
+    }
+
+    /// Takes the oldest event out of the event queue, call this until it returns None.
+    /// The events are derived from the input and the cursor picking, so hover events arrive in render()
+    /// together with the change of currently_hovered_area.
+    pub fn poll_event(&mut self) -> Option<UiEvent> {
+        self.events.pop()
+    }
+
+    /// Maps a position in window pixels onto the virtual canvas, None in the letterbox
+    fn to_canvas(&self, x: u32, y: u32) -> Option<(u32, u32)> {
+        match &self.canvas {
+            Some(canvas) => canvas.to_virtual(x, y),
+            None => Some((x, y)),
+        }
     }
 
     /// Returns the currently hovered area, if any.
+    /// The picking result is read back from the GPU without blocking, so it lags behind the cursor:
+    /// the result of a frame becomes visible in a later render() once the GPU finished that frame,
+    /// usually one or two frames later. Use hit_test for an answer right away.
     pub fn currently_hovered_area(&self) -> Option<UiAreaHandle> {
+        self.cursor_picking_result.map(|hit| hit.area)
+    }
+
+    /// Like currently_hovered_area, but also tells where inside of the area the cursor is.
+    pub fn currently_hovered(&self) -> Option<Hit> {
         self.cursor_picking_result
     }
 
+    /// Returns the area at the given position right away, without waiting for the GPU like currently_hovered_area.
+    /// The position is in window pixels like for set_cursor_pos. Picks like the cursor picking does,
+    /// using CPU copies of the sprites and glyphs and the areas as of the last update.
+    pub fn hit_test(&self, x: u32, y: u32) -> Option<UiAreaHandle> {
+        self.hit_at(x, y).map(|hit| hit.area)
+    }
+
+    /// Like hit_test, but also tells where inside of the area the position is.
+    pub fn hit_at(&self, x: u32, y: u32) -> Option<Hit> {
+        let (x, y) = self.to_canvas(x, y)?;
+        cpu::areas_at(&self.scene, &self.cpu_sprites, &self.cpu_glyphs, x, y)
+            .next()
+            .map(|(hit, _)| hit)
+    }
+
+    /// Returns every area at the given position from top to bottom, together with its z order.
+    /// Useful for drag and drop, to find the drop target below the dragged area. Picks like hit_test.
+    pub fn areas_at(&self, x: u32, y: u32) -> Vec<(UiAreaHandle, ZOrder)> {
+        let Some((x, y)) = self.to_canvas(x, y) else {
+            return Vec::new();
+        };
+        cpu::areas_at(&self.scene, &self.cpu_sprites, &self.cpu_glyphs, x, y)
+            .map(|(hit, z)| (hit.area, z))
+            .collect()
+    }
+
     fn update_cursor(&mut self, device: &Device, queue: &Queue) {
         // Update cursor position
         if self.cursor_moved {
@@ -290,25 +818,44 @@ impl<S: SpriteId, F: FontId> Stgi<S, F> {
             );
         }
 
-        // Get cursor picking result
-        device.poll(wgpu::Maintain::Wait);
-        let mut cursor_picking_result = None;
-        while let Ok(id) = self.cursor_picking_result_receiver.try_recv() {
-            cursor_picking_result = Some(id);
-        }
-        if let Some(id) = cursor_picking_result {
-            if id != 0 {
-                self.cursor_picking_result = Some(UiAreaHandle {
-                    id: NonZeroU32::new(id).unwrap(),
+        // Get the results of all frames the GPU finished, without waiting for the others
+        device.poll(wgpu::Maintain::Poll);
+        while let Ok((slot, frame, result)) = self.cursor_picking_result_receiver.try_recv() {
+            self.cursor_picking_slots[slot].in_flight = false;
+            if let Some([id, local_x, local_y, sprite_frame]) =
+                result.filter(|_| frame > self.cursor_picking_result_frame)
+            {
+                self.cursor_picking_result_frame = frame;
+                self.cursor_picking_result = NonZeroU32::new(id).map(|id| Hit {
+                    area: UiAreaHandle { id },
+                    local_x: f32::from_bits(local_x),
+                    local_y: f32::from_bits(local_y),
+                    frame: sprite_frame,
                 });
-            } else {
-                self.cursor_picking_result = None;
+                self.events.hover(self.currently_hovered_area());
+                self.scene.set_hovered(self.currently_hovered_area());
             }
         }
     }
 
-    /// Call this every time the window is resized.
+    /// Gets the position of a UiArea in window pixels (x_min, x_max, y_min, y_max),
+    /// after applying its parents and placement. Only changes in update().
+    pub fn area_rect(&self, handle: UiAreaHandle) -> Option<(f32, f32, f32, f32)> {
+        self.scene.area_rect(handle)
+    }
+
+    /// Call this every time the window is resized. Areas with a placement are laid out again in the next update.
+    /// With a virtual resolution, only the upscaling of the virtual canvas changes.
+    /// A size of zero, like a minimized window reports, is ignored.
     pub fn resize(&mut self, device: &Device, queue: &Queue, new_width: f32, new_height: f32) {
+        if new_width < 1.0 || new_height < 1.0 {
+            return;
+        }
+        if let Some(canvas) = &mut self.canvas {
+            canvas.resize(new_width as u32, new_height as u32);
+            return;
+        }
+        self.scene.resize(new_width, new_height);
         self.uniform_data.window_width = new_width;
         self.uniform_data.window_height = new_height;
         queue.write_buffer(
@@ -326,7 +873,7 @@ impl<S: SpriteId, F: FontId> Stgi<S, F> {
             mip_level_count: 1,
             sample_count: 1,
             dimension: TextureDimension::D2,
-            format: TextureFormat::R32Uint,
+            format: TextureFormat::Rgba32Uint,
             usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
             view_formats: &[],
         });
@@ -357,18 +904,55 @@ impl<S: SpriteId, F: FontId> Stgi<S, F> {
 
     /// Call this every frame to update the UI, best before rendering.
     pub fn update(&mut self, device: &Device, queue: &Queue) {
-        let needs_text_update = !self.dirty_areas.is_empty()
-            || !self.areas_to_remove.is_empty()
-            || self.recently_cleared;
-        self.handle_dirty_areas(device, queue);
-        if needs_text_update {
-            self.recently_cleared = false;
-            self.text_renderer.update(
-                device,
+        let changes = self.scene.update();
+        if changes.is_empty() {
+            return;
+        }
+        // Changes that keep the draw order, like switching to the hovered sprite, are written in place
+        if changes.structure || !self.update_areas(queue, &changes.dirty) {
+            let text_ranges =
+                self.text_renderer
+                    .update(device, queue, self.scene.areas(), &self.atlas);
+            self.update_draw_commands(device, queue, &text_ranges);
+        }
+    }
+
+    /// Overwrites the instances and glyphs of the given areas in place, all other areas are left untouched.
+    /// Returns false if an area needs another place in the draw commands: it gained or lost its instance,
+    /// its amount of glyphs changed or it became (non-)pickable. The draw commands have to be rebuilt then.
+    fn update_areas(&mut self, queue: &Queue, handles: &[UiAreaHandle]) -> bool {
+        for &handle in handles {
+            let (Some(area), Some(drawn)) = (
+                self.scene.internal_area(handle),
+                self.drawn_areas.get(&handle),
+            ) else {
+                return false;
+            };
+            let instance = Self::instance(&self.atlas, &self.cpu_sprites, handle, area);
+            if instance.is_some() != drawn.instance.is_some()
+                || DrawnArea::picked(area) != drawn.picked
+            {
+                return false;
+            }
+            if !self.text_renderer.update_area(
                 queue,
-                self.ui_areas.iter().map(|(id, area)| (id, &area.area)),
-            );
+                handle,
+                area,
+                drawn.glyphs.clone(),
+                &self.atlas,
+            ) {
+                return false;
+            }
+            if let (Some(index), Some(instance)) = (drawn.instance, instance) {
+                self.instance_buffer.staging[index as usize] = instance;
+                queue.write_buffer(
+                    &self.instance_buffer.buffer,
+                    index as u64 * std::mem::size_of::<Instance>() as u64,
+                    bytemuck::cast_slice(&[instance]),
+                );
+            }
         }
+        true
     }
 
     fn check_index_size(&mut self, device: &Device) {
@@ -381,6 +965,8 @@ impl<S: SpriteId, F: FontId> Stgi<S, F> {
     }
 
     /// Renders the UI. Returns a command buffer that should be submitted to the queue.
+    /// With a virtual resolution, the UI is rendered into the virtual canvas right away and then upscaled into
+    /// the render pass, which changes its viewport.
     #[must_use]
     pub fn render(
         &mut self,
@@ -390,17 +976,32 @@ impl<S: SpriteId, F: FontId> Stgi<S, F> {
     ) -> CommandBuffer {
         self.update_cursor(device, queue);
         self.check_index_size(device);
-        render_pass.set_index_buffer(self.index_buffer.slice(..), IndexFormat::Uint16);
-        render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
-        for i in 0..4 {
-            if let Some(instance_buffer) = &self.instance_buffers[i] {
-                render_pass.set_pipeline(&self.render_pipeline);
-                render_pass.set_bind_group(0, &self.atlas_bind_group, &[]);
-                render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-                render_pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
-                render_pass.draw_indexed(0..6, 0, 0..instance_buffer.size);
+        if let Some(canvas) = &self.canvas {
+            let mut cmds = device.create_command_encoder(&CommandEncoderDescriptor {
+                label: Some("STGI Virtual Canvas Command Encoder"),
+            });
+            {
+                let mut canvas_pass = cmds.begin_render_pass(&RenderPassDescriptor {
+                    label: Some("STGI Virtual Canvas Render Pass"),
+                    color_attachments: &[Some(RenderPassColorAttachment {
+                        view: canvas.view(),
+                        resolve_target: None,
+                        ops: Operations {
+                            load: LoadOp::Clear(Color::TRANSPARENT),
+                            store: StoreOp::Store,
+                        },
+                    })],
+                    depth_stencil_attachment: None,
+                    timestamp_writes: None,
+                    occlusion_query_set: None,
+                });
+                self.draw(&mut canvas_pass);
             }
-            self.text_renderer.render(render_pass, i);
+            // Has to be done before the render pass of the caller is submitted
+            queue.submit([cmds.finish()]);
+            canvas.blit(render_pass);
+        } else {
+            self.draw(render_pass);
         }
 
         // Render cursor picking
@@ -414,7 +1015,7 @@ impl<S: SpriteId, F: FontId> Stgi<S, F> {
                     view: &self.cursor_picking_texture_view,
                     resolve_target: None,
                     ops: Operations {
-                        load: LoadOp::Clear(Color::BLACK),
+                        load: LoadOp::Clear(Color::TRANSPARENT),
                         store: StoreOp::Store,
                     },
                 })],
@@ -422,18 +1023,21 @@ impl<S: SpriteId, F: FontId> Stgi<S, F> {
                 timestamp_writes: None,
                 occlusion_query_set: None,
             });
-            render_pass.set_index_buffer(self.index_buffer.slice(..), IndexFormat::Uint16);
+            render_pass.set_index_buffer(self.index_buffer.slice(..), IndexFormat::Uint32);
             render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
-            for i in 0..4 {
-                if let Some(instance_buffer) = &self.instance_buffers[i] {
-                    render_pass.set_pipeline(&self.cursor_picking_render_pipeline);
-                    render_pass.set_bind_group(0, &self.atlas_bind_group, &[]);
-                    render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-                    render_pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
-                    render_pass.draw_indexed(0..6, 0, 0..instance_buffer.size);
+            for command in &self.picking_commands {
+                match command {
+                    DrawCommand::Sprites(instances) => {
+                        render_pass.set_pipeline(&self.cursor_picking_render_pipeline);
+                        render_pass.set_bind_group(0, self.atlas.bind_group(), &[]);
+                        self.draw_sprites(&mut render_pass, instances.clone());
+                    }
+                    DrawCommand::Text(glyphs) => self.text_renderer.render_cursor_picking(
+                        &mut render_pass,
+                        glyphs.clone(),
+                        self.atlas.bind_group(),
+                    ),
                 }
-                self.text_renderer
-                    .render_cursor_picking(&mut render_pass, i);
             }
         }
 
@@ -448,214 +1052,274 @@ impl<S: SpriteId, F: FontId> Stgi<S, F> {
             compute_pass.set_bind_group(1, &self.cursor_picking_compute_bind_group, &[]);
             compute_pass.dispatch_workgroups(1, 1, 1);
         }
-        cmds.copy_buffer_to_buffer(
-            &self.cursor_picking_result_storage_buffer,
-            0,
-            &self.cursor_picking_result_staging_buffer,
-            0,
-            4,
-        );
+        // If all slots are in flight, the GPU is several frames behind and this result is skipped
+        self.cursor_picking_slot = self
+            .cursor_picking_slots
+            .iter()
+            .position(|slot| !slot.in_flight);
+        if let Some(slot) = self.cursor_picking_slot {
+            cmds.copy_buffer_to_buffer(
+                &self.cursor_picking_result_storage_buffer,
+                0,
+                &self.cursor_picking_slots[slot].buffer,
+                0,
+                16,
+            );
+        }
 
         // Compute cursor picking
         cmds.finish()
     }
 
     /// Call this after submitting the command buffer returned by render().
+    /// Starts reading back the picking result of the frame, this never blocks.
     pub fn post_render_work(&mut self) {
-        let _sender = self.cursor_picking_result_sender.clone();
-        let _buffer = self.cursor_picking_result_staging_buffer.clone();
-        self.cursor_picking_result_staging_buffer
+        let Some(slot) = self.cursor_picking_slot.take() else {
+            return;
+        };
+        self.cursor_picking_frame += 1;
+        let frame = self.cursor_picking_frame;
+        let sender = self.cursor_picking_result_sender.clone();
+        let buffer = self.cursor_picking_slots[slot].buffer.clone();
+        self.cursor_picking_slots[slot].in_flight = true;
+        self.cursor_picking_slots[slot]
+            .buffer
             .slice(..)
             .map_async(wgpu::MapMode::Read, move |v| {
-                if v.is_ok() {
-                    let view = _buffer.slice(..).get_mapped_range();
-                    let id = u32::from_ne_bytes(view[0..4].try_into().unwrap());
-                    let _ = _sender.send(id);
+                let result = v.is_ok().then(|| {
+                    let view = buffer.slice(..).get_mapped_range();
+                    let result: [u32; 4] = bytemuck::pod_read_unaligned(&view);
                     drop(view);
-                    _buffer.unmap();
-                }
+                    buffer.unmap();
+                    result
+                });
+                let _ = sender.send((slot, frame, result));
             });
     }
 
-    fn handle_dirty_areas(&mut self, device: &Device, queue: &Queue) {
-        for handle in self.areas_to_remove.drain(..) {
-            let Some(area) = self.ui_areas.remove(&handle) else {
-                continue;
-            };
-            if let Some(index) = area.instances_index {
-                let index = index as usize;
-                let instance_buffer = self.instance_buffers[area.old_z.to_usize()]
-                    .as_mut()
-                    .unwrap();
-                if instance_buffer.size == 1 {
-                    // We are removing the only element
-                    assert_eq!(index, 0);
-                    self.instance_buffers[area.old_z.to_usize()] = None;
-                } else if index as u32 == instance_buffer.size - 1 {
-                    // We are removing the last element
-                    instance_buffer.size -= 1;
-                    instance_buffer.order.pop();
-                    instance_buffer.staging.pop();
-                } else {
-                    // We are removing an element somewhere else
-                    instance_buffer.order.swap_remove(index);
-                    instance_buffer.staging.swap_remove(index);
-                    instance_buffer.size -= 1;
-                    let swapped_area = self
-                        .ui_areas
-                        .get_mut(&instance_buffer.order[index])
-                        .unwrap();
-                    swapped_area.instances_index = Some(index as u32);
-                    queue.write_buffer(
-                        &instance_buffer.buffer,
-                        (index * std::mem::size_of::<Instance>()) as u64,
-                        bytemuck::cast_slice(&instance_buffer.staging),
-                    );
-                }
-            }
+    /// Renders the UI into an image, without any surface or window. Useful for snapshot tests and headless environments.
+    /// The image has the window size, or the virtual resolution if there is one. Call update() before, like for render().
+    /// The UI is blended onto a transparent background, so the colors of partly transparent pixels are premultiplied.
+    /// Blocks until the GPU is done. Panics if the surface format is not an 8 bit RGBA or BGRA format.
+    pub fn render_to_image(&mut self, device: &Device, queue: &Queue) -> RgbaImage {
+        let bgra = match self.format {
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => false,
+            TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => true,
+            format => panic!("Can not render to an image with format {:?}", format),
+        };
+        self.check_index_size(device);
+        let (width, height) = self.scene.window_size();
+        let (width, height) = (width as u32, height as u32);
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("STGI Image Texture"),
+            size: Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: self.format,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        let bytes_per_row = (width * 4).next_multiple_of(COPY_BYTES_PER_ROW_ALIGNMENT);
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("STGI Image Buffer"),
+            size: bytes_per_row as u64 * height as u64,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut cmds = device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("STGI Image Command Encoder"),
+        });
+        {
+            let mut render_pass = cmds.begin_render_pass(&RenderPassDescriptor {
+                label: Some("STGI Image Render Pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Color::TRANSPARENT),
+                        store: StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            self.draw(&mut render_pass);
         }
+        cmds.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            ImageCopyBuffer {
+                buffer: &buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+        queue.submit([cmds.finish()]);
 
-        for handle in self.dirty_areas.drain(..) {
-            let Some(area) = self.ui_areas.get_mut(&handle) else {
-                continue;
-            };
+        buffer.slice(..).map_async(MapMode::Read, |_| {});
+        device.poll(Maintain::Wait);
+        let data = buffer.slice(..).get_mapped_range();
+        let mut image = RgbaImage::new(width, height);
+        for (y, row) in data.chunks_exact(bytes_per_row as usize).enumerate() {
+            for (x, pixel) in row[..width as usize * 4].chunks_exact(4).enumerate() {
+                let [r, g, b, a] = pixel.try_into().unwrap();
+                let pixel = if bgra { [b, g, r, a] } else { [r, g, b, a] };
+                image.put_pixel(x as u32, y as u32, Rgba(pixel));
+            }
+        }
+        drop(data);
+        buffer.unmap();
+        image
+    }
 
-            // If z-index changed, the area is disabled, or has no sprite then we need to remove it from the buffers first
-            if area.old_z != area.area.z || !area.area.enabled || area.area.sprite.is_none() {
-                if let Some(index) = area.instances_index {
-                    area.instances_index = None;
-                    let index = index as usize;
-                    let instance_buffer = self.instance_buffers[area.old_z.to_usize()]
-                        .as_mut()
-                        .unwrap();
-                    if instance_buffer.size == 1 {
-                        // We are removing the only element
-                        assert_eq!(index, 0);
-                        self.instance_buffers[area.old_z.to_usize()] = None;
-                    } else if index as u32 == instance_buffer.size - 1 {
-                        // We are removing the last element
-                        instance_buffer.size -= 1;
-                        instance_buffer.order.pop();
-                        instance_buffer.staging.pop();
-                    } else {
-                        // We are removing an element somewhere else
-                        instance_buffer.order.swap_remove(index);
-                        instance_buffer.staging.swap_remove(index);
-                        instance_buffer.size -= 1;
-                        let swapped_area = self
-                            .ui_areas
-                            .get_mut(&instance_buffer.order[index])
-                            .unwrap();
-                        swapped_area.instances_index = Some(index as u32);
-                        queue.write_buffer(
-                            &instance_buffer.buffer,
-                            (index * std::mem::size_of::<Instance>()) as u64,
-                            bytemuck::cast_slice(&instance_buffer.staging),
-                        );
-                    }
+    /// Draws all areas in draw order
+    fn draw(&self, render_pass: &mut RenderPass) {
+        render_pass.set_index_buffer(self.index_buffer.slice(..), IndexFormat::Uint32);
+        render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
+        for command in &self.draw_commands {
+            match command {
+                DrawCommand::Sprites(instances) => {
+                    render_pass.set_pipeline(&self.render_pipeline);
+                    render_pass.set_bind_group(0, self.atlas.bind_group(), &[]);
+                    self.draw_sprites(render_pass, instances.clone());
+                }
+                DrawCommand::Text(glyphs) => {
+                    self.text_renderer
+                        .render(render_pass, glyphs.clone(), self.atlas.bind_group())
                 }
             }
+        }
+    }
+
+    /// Draws a range of the instance buffer, the pipeline and atlas bind group must already be set.
+    fn draw_sprites(&self, render_pass: &mut RenderPass, instances: Range<u32>) {
+        // Slicing the buffer instead of using first_instance, which is not supported everywhere
+        let size = std::mem::size_of::<Instance>() as u64;
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_vertex_buffer(
+            1,
+            self.instance_buffer
+                .buffer
+                .slice(instances.start as u64 * size..instances.end as u64 * size),
+        );
+        render_pass.draw_indexed(0..6, 0, 0..instances.len() as u32);
+    }
+
+    /// The instance of an area, None if the area is disabled or has no (registered) sprite
+    fn instance(
+        atlas: &SpriteAtlas<S>,
+        cpu_sprites: &CpuSprites<S>,
+        handle: UiAreaHandle,
+        area: &InternalUiArea<S, F>,
+    ) -> Option<Instance> {
+        let resolved = &area.resolved;
+        if !resolved.enabled {
+            return None;
+        }
+        let sprite_index = atlas.index(area.sprite()?)?;
+        let (animation_mode, animation_value) = area.area.animation.to_instance_data();
+        let [r, g, b, _] = area.area.color;
+        Some(Instance {
+            sprite_index,
+            x_min: resolved.x_min,
+            x_max: resolved.x_max,
+            y_min: resolved.y_min,
+            y_max: resolved.y_max,
+            area_id: handle.id.get(),
+            color: [r, g, b, resolved.opacity],
+            animation_mode,
+            animation_value,
+            clip: ClipRect::to_instance_data(resolved.clip),
+            pick_threshold: cpu_sprites.picking(area).threshold(),
+        })
+    }
 
-            let Some(area) = self.ui_areas.get_mut(&handle) else {
-                continue;
+    /// Rebuilds the instance buffer and the draw commands by walking the draw order.
+    /// text_ranges are the glyph ranges of the areas with text, in draw order.
+    fn update_draw_commands(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        text_ranges: &[(UiAreaHandle, Range<u32>)],
+    ) {
+        let instance_buffer = &mut self.instance_buffer;
+        instance_buffer.staging.clear();
+        self.draw_commands.clear();
+        self.picking_commands.clear();
+        self.drawn_areas.clear();
+        let mut text_ranges = text_ranges.iter().peekable();
+        for (handle, area) in self.scene.areas() {
+            let picked = DrawnArea::picked(area);
+            let mut drawn = DrawnArea {
+                instance: None,
+                glyphs: None,
+                picked,
             };
-            // Update the instance data
-            if area.area.enabled && area.area.sprite.is_some() {
-                if let Some(index) = area.instances_index {
-                    // Overwrite the instance data
-                    let instance_buffer = self.instance_buffers[area.area.z.to_usize()]
-                        .as_mut()
-                        .unwrap();
-                    let Some(sprite_index) =
-                        self.sprite_indices.get(area.area.sprite.as_ref().unwrap())
-                    else {
-                        unreachable!("Sprite: {:?} not registered", area.area.sprite);
-                    };
-                    instance_buffer.staging[index as usize] = Instance {
-                        sprite_index: *sprite_index,
-                        x_min: area.area.x_min,
-                        x_max: area.area.x_max,
-                        y_min: area.area.y_min,
-                        y_max: area.area.y_max,
-                        area_id: handle.id.get(),
-                    };
-                    queue.write_buffer(
-                        &instance_buffer.buffer,
-                        (index as usize * std::mem::size_of::<Instance>()) as u64,
-                        bytemuck::cast_slice(&[instance_buffer.staging[index as usize]]),
-                    );
-                } else {
-                    // Add a new instance
-                    let instance_buffer = self.instance_buffers[area.area.z.to_usize()]
-                        .get_or_insert_with(|| {
-                            let buffer = device.create_buffer(&BufferDescriptor {
-                                label: Some("Instance Buffer"),
-                                size: 128 * std::mem::size_of::<Instance>() as u64,
-                                usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
-                                mapped_at_creation: false,
-                            });
-                            InstanceBuffer {
-                                staging: Vec::new(),
-                                order: Vec::new(),
-                                buffer,
-                                capacity: 128 * std::mem::size_of::<Instance>() as u32,
-                                size: 0,
-                            }
-                        });
-                    let Some(sprite_index) =
-                        self.sprite_indices.get(area.area.sprite.as_ref().unwrap())
-                    else {
-                        unreachable!("Sprite: {:?} not registered", area.area.sprite);
-                    };
-                    if instance_buffer.size == instance_buffer.capacity {
-                        // Resize the buffer
-                        let new_capacity = instance_buffer.capacity * 2;
-                        let new_buffer = device.create_buffer(&BufferDescriptor {
-                            label: Some("Instance Buffer"),
-                            size: new_capacity as u64 * std::mem::size_of::<Instance>() as u64,
-                            usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
-                            mapped_at_creation: false,
-                        });
-                        queue.write_buffer(
-                            &new_buffer,
-                            0,
-                            bytemuck::cast_slice(&instance_buffer.staging),
-                        );
-                        instance_buffer.capacity = new_capacity;
-                        instance_buffer.buffer = new_buffer;
-                    }
-                    instance_buffer.staging.push(Instance {
-                        sprite_index: *sprite_index,
-                        x_min: area.area.x_min,
-                        x_max: area.area.x_max,
-                        y_min: area.area.y_min,
-                        y_max: area.area.y_max,
-                        area_id: handle.id.get(),
-                    });
-                    instance_buffer.order.push(handle);
-                    area.instances_index = Some(instance_buffer.size);
-                    queue.write_buffer(
-                        &instance_buffer.buffer,
-                        (instance_buffer.size as usize * std::mem::size_of::<Instance>()) as u64,
-                        bytemuck::cast_slice(&[*instance_buffer.staging.last().unwrap()]),
-                    );
-                    instance_buffer.size += 1;
+            if let Some(instance) = Self::instance(&self.atlas, &self.cpu_sprites, handle, area) {
+                let index = instance_buffer.staging.len() as u32;
+                instance_buffer.staging.push(instance);
+                drawn.instance = Some(index);
+                let command = DrawCommand::Sprites(index..index + 1);
+                if picked.0 {
+                    DrawCommand::push(&mut self.picking_commands, command.clone());
+                }
+                DrawCommand::push(&mut self.draw_commands, command);
+            }
+            // The text has to be drawn on top of this sprite, but below all following ones
+            if let Some((_, glyphs)) =
+                text_ranges.next_if(|(text_handle, _)| *text_handle == handle)
+            {
+                drawn.glyphs = Some(glyphs.clone());
+                let command = DrawCommand::Text(glyphs.clone());
+                if picked.1 {
+                    DrawCommand::push(&mut self.picking_commands, command.clone());
                 }
+                DrawCommand::push(&mut self.draw_commands, command);
             }
+            self.drawn_areas.insert(handle, drawn);
+        }
+
+        if instance_buffer.staging.len() as u32 > instance_buffer.capacity {
+            instance_buffer.capacity =
+                (instance_buffer.capacity * 2).max(instance_buffer.staging.len() as u32);
+            instance_buffer.buffer = device.create_buffer(&BufferDescriptor {
+                label: Some("Instance Buffer"),
+                size: instance_buffer.capacity as u64 * std::mem::size_of::<Instance>() as u64,
+                usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+        }
+        if !instance_buffer.staging.is_empty() {
+            queue.write_buffer(
+                &instance_buffer.buffer,
+                0,
+                bytemuck::cast_slice(&instance_buffer.staging),
+            );
         }
     }
 
     fn set_index_buffer(&mut self, device: &Device, amount_indices: usize) {
-        assert!(amount_indices % 6 == 0);
-        let mut indices: Vec<u16> = Vec::with_capacity(amount_indices);
+        assert!(amount_indices.is_multiple_of(6));
+        // 32 bit, every glyph can have up to three quads with outline and shadow
+        let mut indices: Vec<u32> = Vec::with_capacity(amount_indices);
         for i in 0..amount_indices / 6 {
-            let i = i * 4;
-            indices.push(i as u16);
-            indices.push(i as u16 + 1);
-            indices.push(i as u16 + 2);
-            indices.push(i as u16);
-            indices.push(i as u16 + 2);
-            indices.push(i as u16 + 3);
+            let i = i as u32 * 4;
+            indices.extend_from_slice(&[i, i + 1, i + 2, i, i + 2, i + 3]);
         }
         self.index_buffer_size = indices.len() as u32;
         self.index_buffer = device.create_buffer_init(&BufferInitDescriptor {
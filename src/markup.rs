@@ -0,0 +1,458 @@
+// A BBCode-like markup for styled text, so dialogue can be written in data files instead of building spans by hand.
+// Parsing never panics, malformed markup is reported with the byte position where it went wrong.
+
+use std::fmt::{self, Display};
+
+use image::Rgba;
+
+use super::{
+    software::decode,
+    text::{FontId, TextEffect},
+    SpriteId, Text, TextSpan,
+};
+
+/// The largest [size=N]. Even wide glyphs of this size fit into the smallest glyph atlas texture wgpu guarantees,
+/// so a data file can't make rendering panic.
+pub const MAX_SIZE: u16 = 512;
+
+/// Why Text::set_markup rejected its markup. Positions are byte offsets into the markup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MarkupError {
+    /// A [ without a matching ], use [[ for a literal [
+    UnterminatedTag {
+        position: usize,
+    },
+    UnknownTag {
+        tag: String,
+        position: usize,
+    },
+    /// The value of a tag is missing, malformed or given to a tag that takes none
+    InvalidValue {
+        tag: String,
+        value: String,
+        position: usize,
+    },
+    /// The font resolver returned None for the name
+    UnknownFont {
+        name: String,
+        position: usize,
+    },
+    /// The sprite resolver returned None for the name of an icon
+    UnknownSprite {
+        name: String,
+        position: usize,
+    },
+    /// A closing tag that doesn't close the innermost open tag, expected is None if no tag is open
+    UnexpectedClosingTag {
+        tag: String,
+        expected: Option<String>,
+        position: usize,
+    },
+    /// The markup ended while the tag was still open, position is the one of the opening tag
+    UnclosedTag {
+        tag: String,
+        position: usize,
+    },
+}
+
+impl Display for MarkupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MarkupError::UnterminatedTag { position } => {
+                write!(f, "tag at {position} is missing its closing ]")
+            }
+            MarkupError::UnknownTag { tag, position } => {
+                write!(f, "unknown tag [{tag}] at {position}")
+            }
+            MarkupError::InvalidValue {
+                tag,
+                value,
+                position,
+            } => write!(f, "invalid value \"{value}\" of tag [{tag}] at {position}"),
+            MarkupError::UnknownFont { name, position } => {
+                write!(f, "unknown font \"{name}\" at {position}")
+            }
+            MarkupError::UnknownSprite { name, position } => {
+                write!(f, "unknown sprite \"{name}\" at {position}")
+            }
+            MarkupError::UnexpectedClosingTag {
+                tag,
+                expected: Some(expected),
+                position,
+            } => write!(f, "[/{tag}] at {position} closes [{expected}]"),
+            MarkupError::UnexpectedClosingTag {
+                tag,
+                expected: None,
+                position,
+            } => write!(f, "[/{tag}] at {position} closes no tag"),
+            MarkupError::UnclosedTag { tag, position } => {
+                write!(f, "tag [{tag}] at {position} is never closed")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MarkupError {}
+
+/// The style a tag changes
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Style<F: FontId> {
+    font: F,
+    size: u16,
+    color: [f32; 4],
+    underline: bool,
+    effect: TextEffect,
+}
+
+/// A tag that is open, with the style to restore when it is closed
+struct OpenTag<'a, F: FontId> {
+    name: &'a str,
+    position: usize,
+    outer: Style<F>,
+}
+
+/// The text and the spans set_markup builds
+struct Output<S: SpriteId, F: FontId> {
+    base: Style<F>,
+    text: String,
+    spans: Vec<TextSpan<S, F>>,
+}
+
+impl<S: SpriteId, F: FontId> Output<S, F> {
+    /// Text before the first tag keeps the style of the Text itself, everything else becomes spans
+    fn push_text(&mut self, run: &mut String, style: Style<F>) {
+        if run.is_empty() {
+            return;
+        }
+        let run = std::mem::take(run);
+        if self.spans.is_empty() && style == self.base {
+            self.text.push_str(&run);
+            return;
+        }
+        if let Some(last) = self
+            .spans
+            .last_mut()
+            .filter(|last| last.sprite.is_none() && span_style(last) == style)
+        {
+            last.text.push_str(&run);
+            return;
+        }
+        self.spans.push(TextSpan {
+            text: run,
+            ..span(style, None)
+        });
+    }
+
+    fn push_sprite(&mut self, style: Style<F>, sprite: S) {
+        self.spans.push(span(style, Some(sprite)));
+    }
+}
+
+fn span<S: SpriteId, F: FontId>(style: Style<F>, sprite: Option<S>) -> TextSpan<S, F> {
+    TextSpan {
+        text: String::new(),
+        font: style.font,
+        size: style.size,
+        color: style.color,
+        underline: style.underline,
+        effect: style.effect,
+        sprite,
+    }
+}
+
+impl<S: SpriteId, F: FontId> Text<S, F> {
+    /// Replaces text and spans with the given markup. Untagged text has the style of this Text, tags change it:
+    /// - [color=#rrggbb] or [color=#rrggbbaa], sRGB hex like in image editors
+    /// - [font=name], resolved with fonts
+    /// - [size=16], from 1 to MAX_SIZE
+    /// - [u] for underline
+    /// - [wave] and [shake], see TextEffect
+    ///
+    /// Every tag must be closed with [/name], innermost first. [[ is a literal [.
+    /// [icon=name] places an inline sprite resolved with sprites, in the current style and without closing tag,
+    /// see TextSpan::sprite.
+    /// Returns an error and leaves the text untouched if the markup is malformed.
+    pub fn set_markup(
+        &mut self,
+        markup: &str,
+        fonts: impl Fn(&str) -> Option<F>,
+        sprites: impl Fn(&str) -> Option<S>,
+    ) -> Result<(), MarkupError> {
+        let base = Style {
+            font: self.font,
+            size: self.size,
+            color: self.color,
+            underline: self.underline,
+            effect: self.effect,
+        };
+        let mut style = base;
+        let mut open: Vec<OpenTag<F>> = Vec::new();
+        let mut output = Output {
+            base,
+            text: String::new(),
+            spans: Vec::new(),
+        };
+        let mut run = String::new();
+
+        let mut rest = markup;
+        while let Some(start) = rest.find('[') {
+            run.push_str(&rest[..start]);
+            let position = markup.len() - rest.len() + start;
+            let after = &rest[start + 1..];
+            if let Some(after) = after.strip_prefix('[') {
+                run.push('[');
+                rest = after;
+                continue;
+            }
+            let Some(end) = after.find(']') else {
+                return Err(MarkupError::UnterminatedTag { position });
+            };
+            let tag = &after[..end];
+            rest = &after[end + 1..];
+
+            if let Some(name) = tag.strip_prefix('/') {
+                let Some(last) = open.last().filter(|last| last.name == name) else {
+                    return Err(MarkupError::UnexpectedClosingTag {
+                        tag: name.to_owned(),
+                        expected: open.last().map(|last| last.name.to_owned()),
+                        position,
+                    });
+                };
+                output.push_text(&mut run, style);
+                style = last.outer;
+                open.pop();
+                continue;
+            }
+
+            let (name, value) = match tag.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (tag, None),
+            };
+            let invalid = || MarkupError::InvalidValue {
+                tag: name.to_owned(),
+                value: value.unwrap_or_default().to_owned(),
+                position,
+            };
+            if let ("icon", Some(value)) = (name, value) {
+                let sprite = sprites(value).ok_or_else(|| MarkupError::UnknownSprite {
+                    name: value.to_owned(),
+                    position,
+                })?;
+                output.push_text(&mut run, style);
+                output.push_sprite(style, sprite);
+                continue;
+            }
+            let mut new_style = style;
+            match (name, value) {
+                ("color", Some(value)) => {
+                    new_style.color = parse_color(value).ok_or_else(invalid)?
+                }
+                ("font", Some(value)) => {
+                    new_style.font = fonts(value).ok_or_else(|| MarkupError::UnknownFont {
+                        name: value.to_owned(),
+                        position,
+                    })?
+                }
+                ("size", Some(value)) => {
+                    new_style.size = value
+                        .parse()
+                        .ok()
+                        .filter(|&size| (1..=MAX_SIZE).contains(&size))
+                        .ok_or_else(invalid)?
+                }
+                ("u", None) => new_style.underline = true,
+                ("wave", None) => new_style.effect = TextEffect::Wave,
+                ("shake", None) => new_style.effect = TextEffect::Shake,
+                ("color" | "font" | "size" | "u" | "wave" | "shake" | "icon", _) => {
+                    return Err(invalid())
+                }
+                _ => {
+                    return Err(MarkupError::UnknownTag {
+                        tag: name.to_owned(),
+                        position,
+                    })
+                }
+            }
+            output.push_text(&mut run, style);
+            open.push(OpenTag {
+                name,
+                position,
+                outer: style,
+            });
+            style = new_style;
+        }
+        if let Some(tag) = open.pop() {
+            return Err(MarkupError::UnclosedTag {
+                tag: tag.name.to_owned(),
+                position: tag.position,
+            });
+        }
+        run.push_str(rest);
+        output.push_text(&mut run, style);
+
+        self.text = output.text;
+        self.spans = output.spans;
+        Ok(())
+    }
+}
+
+fn span_style<S: SpriteId, F: FontId>(span: &TextSpan<S, F>) -> Style<F> {
+    Style {
+        font: span.font,
+        size: span.size,
+        color: span.color,
+        underline: span.underline,
+        effect: span.effect,
+    }
+}
+
+/// #rrggbb or #rrggbbaa in sRGB to a linear color
+fn parse_color(value: &str) -> Option<[f32; 4]> {
+    let hex = value.strip_prefix('#')?;
+    if !matches!(hex.len(), 6 | 8) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |index: usize| u8::from_str_radix(hex.get(index * 2..index * 2 + 2)?, 16).ok();
+    let alpha = match hex.len() {
+        8 => channel(3)?,
+        _ => 255,
+    };
+    Some(decode(Rgba([channel(0)?, channel(1)?, channel(2)?, alpha])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn markup(markup: &str) -> Result<Text<&'static str, &'static str>, MarkupError> {
+        let mut text = Text::new("regular", 16, "");
+        text.set_markup(
+            markup,
+            |name| ["regular", "bold"].into_iter().find(|&font| font == name),
+            |name| ["coin"].into_iter().find(|&sprite| sprite == name),
+        )?;
+        Ok(text)
+    }
+
+    #[test]
+    fn escaped_bracket() {
+        let text = markup("a [[b] [u][[c][/u]").unwrap();
+        assert_eq!(text.text, "a [b] ");
+        assert_eq!(text.spans.len(), 1);
+        assert_eq!(text.spans[0].text, "[c]");
+        assert!(text.spans[0].underline);
+    }
+
+    #[test]
+    fn icon() {
+        let text = markup("Pay 5 [size=8][icon=coin][/size] now").unwrap();
+        assert_eq!(text.text, "Pay 5 ");
+        assert_eq!(text.spans.len(), 2);
+        assert_eq!(text.spans[0].sprite, Some("coin"));
+        assert_eq!(text.spans[0].size, 8);
+        assert_eq!(text.spans[1].text, " now");
+        assert_eq!(text.spans[1].sprite, None);
+        assert_eq!(text.spans[1].size, 16);
+    }
+
+    #[test]
+    fn errors() {
+        for (input, error) in [
+            ("ab [color", MarkupError::UnterminatedTag { position: 3 }),
+            (
+                "[blink]x[/blink]",
+                MarkupError::UnknownTag {
+                    tag: "blink".into(),
+                    position: 0,
+                },
+            ),
+            (
+                "x [size=big]y[/size]",
+                MarkupError::InvalidValue {
+                    tag: "size".into(),
+                    value: "big".into(),
+                    position: 2,
+                },
+            ),
+            (
+                "[size=513]x[/size]",
+                MarkupError::InvalidValue {
+                    tag: "size".into(),
+                    value: "513".into(),
+                    position: 0,
+                },
+            ),
+            (
+                "[u=1]x[/u]",
+                MarkupError::InvalidValue {
+                    tag: "u".into(),
+                    value: "1".into(),
+                    position: 0,
+                },
+            ),
+            (
+                "[icon]",
+                MarkupError::InvalidValue {
+                    tag: "icon".into(),
+                    value: "".into(),
+                    position: 0,
+                },
+            ),
+            (
+                "[font=italic]x[/font]",
+                MarkupError::UnknownFont {
+                    name: "italic".into(),
+                    position: 0,
+                },
+            ),
+            (
+                "a [icon=gem]",
+                MarkupError::UnknownSprite {
+                    name: "gem".into(),
+                    position: 2,
+                },
+            ),
+            (
+                "[u]a[/wave]",
+                MarkupError::UnexpectedClosingTag {
+                    tag: "wave".into(),
+                    expected: Some("u".into()),
+                    position: 4,
+                },
+            ),
+            (
+                "a[/u]",
+                MarkupError::UnexpectedClosingTag {
+                    tag: "u".into(),
+                    expected: None,
+                    position: 1,
+                },
+            ),
+            (
+                "a [u]b",
+                MarkupError::UnclosedTag {
+                    tag: "u".into(),
+                    position: 2,
+                },
+            ),
+        ] {
+            assert_eq!(markup(input).unwrap_err(), error, "{input}");
+        }
+    }
+
+    #[test]
+    fn max_size() {
+        let text = markup("[size=512]x[/size]").unwrap();
+        assert_eq!(text.spans[0].size, MAX_SIZE);
+    }
+
+    #[test]
+    fn error_leaves_text_untouched() {
+        let mut text = Text::new("regular", 16, "before");
+        text.spans.push(TextSpan::new("bold", 16, " spans"));
+        let result = text.set_markup("[u]after", |_| None, |_: &str| None::<&str>);
+        assert!(result.is_err());
+        assert_eq!(text.text, "before");
+        assert_eq!(text.spans.len(), 1);
+    }
+}
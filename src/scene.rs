@@ -0,0 +1,584 @@
+// The scene keeps track of all areas: their hierarchy, placement, draw order and which of them changed.
+// It knows nothing about rendering, so it is shared by the GPU renderer (Stgi) and the software renderer.
+
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use ahash::HashMap;
+
+use super::{AreaAnimation, ClipRect, FontId, SpriteId, UiArea, UiAreaHandle, ZOrder};
+
+pub(crate) struct InternalUiArea<S: SpriteId, F: FontId> {
+    // The z under which the area is stored in the draw order
+    pub old_z: ZOrder,
+    // The parent under which the area is stored as a child
+    pub old_parent: Option<UiAreaHandle>,
+    // Sorted
+    pub children: Vec<UiAreaHandle>,
+    pub resolved: ResolvedArea,
+    pub state: AreaState,
+    pub area: UiArea<S, F>,
+}
+
+impl<S: SpriteId, F: FontId> InternalUiArea<S, F> {
+    /// The sprite shown in the current state, see StateSprites
+    pub fn sprite(&self) -> Option<&S> {
+        let states = &self.area.states;
+        if self.area.disabled {
+            return states.disabled.as_ref().or(self.area.sprite.as_ref());
+        }
+        [
+            (self.state.pressed, &states.pressed),
+            (self.state.hovered, &states.hovered),
+            (self.state.focused, &states.focused),
+        ]
+        .into_iter()
+        .find_map(|(active, sprite)| sprite.as_ref().filter(|_| active))
+        .or(self.area.sprite.as_ref())
+    }
+}
+
+/// The interaction state of an area, it picks the sprite out of UiArea::states
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct AreaState {
+    pub hovered: bool,
+    pub pressed: bool,
+    pub focused: bool,
+}
+
+/// Absolute values of an area after applying all of its parents
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ResolvedArea {
+    pub x_min: f32,
+    pub x_max: f32,
+    pub y_min: f32,
+    pub y_max: f32,
+    pub enabled: bool,
+    pub opacity: f32,
+    pub clip: Option<ClipRect>,
+}
+
+impl ResolvedArea {
+    /// window is the size of the window, used instead of the parent for areas without one
+    fn new<S: SpriteId, F: FontId>(
+        area: &UiArea<S, F>,
+        parent: Option<&ResolvedArea>,
+        window: (f32, f32),
+    ) -> Self {
+        let (x, y, parent_width, parent_height) = match parent {
+            Some(parent) => (
+                parent.x_min,
+                parent.y_min,
+                parent.x_max - parent.x_min,
+                parent.y_max - parent.y_min,
+            ),
+            None => (0.0, 0.0, window.0, window.1),
+        };
+        let (x_min, x_max, y_min, y_max) = match &area.placement {
+            Some(placement) => placement.resolve(parent_width, parent_height),
+            None => (area.x_min, area.x_max, area.y_min, area.y_max),
+        };
+        let clip = area.clip.map(|clip| clip.offset(x, y));
+        Self {
+            x_min: x_min + x,
+            x_max: x_max + x,
+            y_min: y_min + y,
+            y_max: y_max + y,
+            enabled: area.enabled && parent.is_none_or(|parent| parent.enabled),
+            opacity: area.color[3] * parent.map_or(1.0, |parent| parent.opacity),
+            clip: match (clip, parent.and_then(|parent| parent.clip)) {
+                (Some(clip), Some(parent_clip)) => Some(clip.intersection(parent_clip)),
+                (clip, parent_clip) => clip.or(parent_clip),
+            },
+        }
+    }
+
+    /// Whether a position in window pixels lies outside of the clip rect
+    pub fn clipped(&self, x: f32, y: f32) -> bool {
+        self.clip.is_some_and(|clip| {
+            x < clip.x_min || x > clip.x_max || y < clip.y_min || y > clip.y_max
+        })
+    }
+}
+
+/// What Scene::update changed, so the renderers only rebuild what they have to
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SceneUpdate {
+    /// Areas were added or removed, or changed their z order or parent
+    pub structure: bool,
+    /// The areas that were resolved again, sorted
+    pub dirty: Vec<UiAreaHandle>,
+}
+
+impl SceneUpdate {
+    pub fn is_empty(&self) -> bool {
+        !self.structure && self.dirty.is_empty()
+    }
+}
+
+pub(crate) struct Scene<S: SpriteId, F: FontId> {
+    next_area_id: NonZeroU32,
+    ui_areas: HashMap<UiAreaHandle, InternalUiArea<S, F>>,
+    dirty_areas: Vec<UiAreaHandle>,
+    areas_to_remove: Vec<UiAreaHandle>,
+    // Areas were added, removed or moved in the draw order or the hierarchy since the last update
+    structure_changed: bool,
+    // All areas sorted by z, then by handle
+    draw_order: Vec<(ZOrder, UiAreaHandle)>,
+    window_size: (f32, f32),
+    animation_time: Duration,
+    hovered: Option<UiAreaHandle>,
+    focused: Option<UiAreaHandle>,
+}
+
+impl<S: SpriteId, F: FontId> Scene<S, F> {
+    pub fn new(window_width: f32, window_height: f32) -> Self {
+        Self {
+            next_area_id: NonZeroU32::new(1).unwrap(),
+            ui_areas: HashMap::default(),
+            dirty_areas: Vec::new(),
+            areas_to_remove: Vec::new(),
+            structure_changed: false,
+            draw_order: Vec::new(),
+            window_size: (window_width, window_height),
+            animation_time: Duration::ZERO,
+            hovered: None,
+            focused: None,
+        }
+    }
+
+    pub fn add_area(&mut self, area: UiArea<S, F>) -> UiAreaHandle {
+        let handle = UiAreaHandle {
+            id: self.next_area_id,
+        };
+        self.next_area_id = self.next_area_id.checked_add(1).unwrap();
+        self.ui_areas.insert(
+            handle,
+            InternalUiArea {
+                old_z: area.z,
+                old_parent: None,
+                children: Vec::new(),
+                resolved: ResolvedArea::new(&area, None, self.window_size),
+                state: AreaState::default(),
+                area,
+            },
+        );
+        let key = (self.ui_areas[&handle].old_z, handle);
+        if let Err(index) = self.draw_order.binary_search(&key) {
+            self.draw_order.insert(index, key);
+        }
+        self.structure_changed = true;
+        self.mark_dirty(handle);
+        handle
+    }
+
+    pub fn area(&self, handle: UiAreaHandle) -> Option<&UiArea<S, F>> {
+        self.ui_areas.get(&handle).map(|area| &area.area)
+    }
+
+    pub fn remove_area(&mut self, area: UiAreaHandle) {
+        match self.areas_to_remove.binary_search(&area) {
+            Ok(_) => {}
+            Err(index) => {
+                self.areas_to_remove.insert(index, area);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.ui_areas.clear();
+        self.dirty_areas.clear();
+        self.areas_to_remove.clear();
+        self.draw_order.clear();
+        self.hovered = None;
+        self.focused = None;
+        self.structure_changed = true;
+    }
+
+    /// The area together with its resolved values, as of the last update
+    pub fn internal_area(&self, handle: UiAreaHandle) -> Option<&InternalUiArea<S, F>> {
+        self.ui_areas.get(&handle)
+    }
+
+    pub fn area_mut(&mut self, handle: UiAreaHandle) -> Option<&mut UiArea<S, F>> {
+        if self.ui_areas.contains_key(&handle) {
+            self.mark_dirty(handle);
+            return self.ui_areas.get_mut(&handle).map(|area| &mut area.area);
+        }
+        None
+    }
+
+    pub fn children(&self, handle: UiAreaHandle) -> &[UiAreaHandle] {
+        self.ui_areas
+            .get(&handle)
+            .map_or(&[], |area| area.children.as_slice())
+    }
+
+    pub fn area_rect(&self, handle: UiAreaHandle) -> Option<(f32, f32, f32, f32)> {
+        self.ui_areas.get(&handle).map(|area| {
+            let resolved = &area.resolved;
+            (
+                resolved.x_min,
+                resolved.x_max,
+                resolved.y_min,
+                resolved.y_max,
+            )
+        })
+    }
+
+    /// All areas in draw order, from bottom to top
+    pub fn areas(&self) -> impl DoubleEndedIterator<Item = (UiAreaHandle, &InternalUiArea<S, F>)> {
+        self.draw_order
+            .iter()
+            .map(|(_, handle)| (*handle, &self.ui_areas[handle]))
+    }
+
+    pub fn window_size(&self) -> (f32, f32) {
+        self.window_size
+    }
+
+    /// Areas with a placement are laid out again in the next update
+    pub fn resize(&mut self, width: f32, height: f32) {
+        self.window_size = (width, height);
+        // Children are laid out relative to their parent, which is already covered by mark_dirty
+        let placed_roots: Vec<UiAreaHandle> = self
+            .ui_areas
+            .iter()
+            .filter(|(_, area)| area.area.placement.is_some() && area.old_parent.is_none())
+            .map(|(handle, _)| *handle)
+            .collect();
+        for handle in placed_roots {
+            self.mark_dirty(handle);
+        }
+    }
+
+    pub fn animation_time(&self) -> Duration {
+        self.animation_time
+    }
+
+    pub fn advance_animations(&mut self, delta: Duration) {
+        self.animation_time += delta;
+    }
+
+    pub fn restart_animation(&mut self, handle: UiAreaHandle) {
+        let now = self.animation_time;
+        if let Some(area) = self.area_mut(handle) {
+            area.animation = AreaAnimation::Playing { start: now };
+        }
+    }
+
+    pub fn pause_animation(&mut self, handle: UiAreaHandle) {
+        let now = self.animation_time;
+        if let Some(area) = self.area_mut(handle) {
+            if let AreaAnimation::Playing { start } = area.animation {
+                area.animation = AreaAnimation::Paused {
+                    elapsed: now.saturating_sub(start),
+                };
+            }
+        }
+    }
+
+    pub fn resume_animation(&mut self, handle: UiAreaHandle) {
+        let now = self.animation_time;
+        if let Some(area) = self.area_mut(handle) {
+            if let AreaAnimation::Paused { elapsed } = area.animation {
+                area.animation = AreaAnimation::Playing {
+                    start: now.saturating_sub(elapsed),
+                };
+            }
+        }
+    }
+
+    pub fn seek_animation(&mut self, handle: UiAreaHandle, frame: u32) {
+        if let Some(area) = self.area_mut(handle) {
+            area.animation = AreaAnimation::Frame(frame);
+        }
+    }
+
+    pub fn set_hovered(&mut self, handle: Option<UiAreaHandle>) {
+        if let Some(old) = std::mem::replace(&mut self.hovered, handle) {
+            self.set_state(old, |state| state.hovered = false);
+        }
+        if let Some(new) = handle {
+            self.set_state(new, |state| state.hovered = true);
+        }
+    }
+
+    pub fn set_pressed(&mut self, handle: UiAreaHandle, pressed: bool) {
+        self.set_state(handle, |state| state.pressed = pressed);
+    }
+
+    pub fn focused(&self) -> Option<UiAreaHandle> {
+        self.focused
+            .filter(|handle| self.ui_areas.contains_key(handle))
+    }
+
+    pub fn set_focused(&mut self, handle: Option<UiAreaHandle>) {
+        if let Some(old) = std::mem::replace(&mut self.focused, handle) {
+            self.set_state(old, |state| state.focused = false);
+        }
+        if let Some(new) = handle {
+            self.set_state(new, |state| state.focused = true);
+        }
+    }
+
+    /// Changes the state of an area, it is only marked dirty if that changes the sprite it shows
+    fn set_state(&mut self, handle: UiAreaHandle, f: impl FnOnce(&mut AreaState)) {
+        let Some(area) = self.ui_areas.get_mut(&handle) else {
+            return;
+        };
+        let old_sprite = area.sprite().cloned();
+        f(&mut area.state);
+        if area.sprite() != old_sprite.as_ref() {
+            if let Err(index) = self.dirty_areas.binary_search(&handle) {
+                self.dirty_areas.insert(index, handle);
+            }
+        }
+    }
+
+    /// Marks all areas showing the sprite as dirty, after it was added, replaced or removed
+    pub fn mark_sprite_dirty(&mut self, sprite_id: &S) {
+        for (handle, area) in self.ui_areas.iter() {
+            // Inline sprites take space in the layout of the text
+            let inline = area.area.text.as_ref().is_some_and(|text| {
+                text.spans
+                    .iter()
+                    .any(|span| span.sprite.as_ref() == Some(sprite_id))
+            });
+            if area.sprite() == Some(sprite_id) || inline {
+                if let Err(index) = self.dirty_areas.binary_search(handle) {
+                    self.dirty_areas.insert(index, *handle);
+                }
+            }
+        }
+    }
+
+    /// Marks the area and all of its children as dirty
+    fn mark_dirty(&mut self, handle: UiAreaHandle) {
+        let mut stack = vec![handle];
+        while let Some(handle) = stack.pop() {
+            if let Err(index) = self.dirty_areas.binary_search(&handle) {
+                self.dirty_areas.insert(index, handle);
+            }
+            if let Some(area) = self.ui_areas.get(&handle) {
+                stack.extend_from_slice(&area.children);
+            }
+        }
+    }
+
+    /// Whether ancestor is the area itself or one of its (grand)parents
+    fn is_ancestor(&self, ancestor: UiAreaHandle, handle: UiAreaHandle) -> bool {
+        let mut current = Some(handle);
+        while let Some(handle) = current {
+            if handle == ancestor {
+                return true;
+            }
+            current = self.ui_areas.get(&handle).and_then(|area| area.old_parent);
+        }
+        false
+    }
+
+    fn depth(&self, handle: UiAreaHandle) -> u32 {
+        let mut depth = 0;
+        let mut current = self.ui_areas[&handle].old_parent;
+        while let Some(parent) = current {
+            depth += 1;
+            current = self.ui_areas[&parent].old_parent;
+        }
+        depth
+    }
+
+    /// Removes and resolves all areas that changed since the last update and returns what changed
+    pub fn update(&mut self) -> SceneUpdate {
+        let mut areas_to_remove = std::mem::take(&mut self.areas_to_remove);
+        while let Some(handle) = areas_to_remove.pop() {
+            let Some(area) = self.ui_areas.remove(&handle) else {
+                continue;
+            };
+            self.structure_changed = true;
+            if let Ok(index) = self.draw_order.binary_search(&(area.old_z, handle)) {
+                self.draw_order.remove(index);
+            }
+            if let Some(parent) = area
+                .old_parent
+                .and_then(|parent| self.ui_areas.get_mut(&parent))
+            {
+                if let Ok(index) = parent.children.binary_search(&handle) {
+                    parent.children.remove(index);
+                }
+            }
+            // Children are removed together with their parent, unless they were moved to another one
+            areas_to_remove.extend(area.children.into_iter().filter(|child| {
+                self.ui_areas
+                    .get(child)
+                    .is_some_and(|child| child.area.parent == Some(handle))
+            }));
+        }
+        self.areas_to_remove = areas_to_remove;
+
+        let mut dirty_areas = std::mem::take(&mut self.dirty_areas);
+        dirty_areas.retain(|handle| self.ui_areas.contains_key(handle));
+
+        // If the parent changed, the area has to move in the hierarchy
+        for &handle in &dirty_areas {
+            let area = &self.ui_areas[&handle];
+            // A parent that would make a cycle is ignored like a missing one
+            let parent = area.area.parent.filter(|&parent| {
+                self.ui_areas.contains_key(&parent) && !self.is_ancestor(handle, parent)
+            });
+            let old_parent = area.old_parent;
+            if parent == old_parent {
+                continue;
+            }
+            if let Some(old_parent) = old_parent.and_then(|old| self.ui_areas.get_mut(&old)) {
+                if let Ok(index) = old_parent.children.binary_search(&handle) {
+                    old_parent.children.remove(index);
+                }
+            }
+            if let Some(parent) = parent.and_then(|parent| self.ui_areas.get_mut(&parent)) {
+                if let Err(index) = parent.children.binary_search(&handle) {
+                    parent.children.insert(index, handle);
+                }
+            }
+            self.ui_areas.get_mut(&handle).unwrap().old_parent = parent;
+            self.structure_changed = true;
+        }
+
+        // Parents have to be resolved before their children
+        let mut resolve_order: Vec<(u32, UiAreaHandle)> = dirty_areas
+            .iter()
+            .map(|&handle| (self.depth(handle), handle))
+            .collect();
+        resolve_order.sort_unstable();
+
+        for (_, handle) in resolve_order {
+            let parent = self.ui_areas[&handle]
+                .old_parent
+                .map(|parent| self.ui_areas[&parent].resolved);
+            let area = self.ui_areas.get_mut(&handle).unwrap();
+            area.resolved = ResolvedArea::new(&area.area, parent.as_ref(), self.window_size);
+
+            // If z-index changed, the area has to move in the draw order
+            if area.old_z != area.area.z {
+                if let Ok(index) = self.draw_order.binary_search(&(area.old_z, handle)) {
+                    self.draw_order.remove(index);
+                }
+                area.old_z = area.area.z;
+                if let Err(index) = self.draw_order.binary_search(&(area.old_z, handle)) {
+                    self.draw_order.insert(index, (area.old_z, handle));
+                }
+                self.structure_changed = true;
+            }
+        }
+        SceneUpdate {
+            structure: std::mem::take(&mut self.structure_changed),
+            dirty: dirty_areas,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(x: f32, y: f32, parent: Option<UiAreaHandle>) -> UiArea<u32, u32> {
+        UiArea {
+            x_min: x,
+            x_max: x + 10.0,
+            y_min: y,
+            y_max: y + 10.0,
+            parent,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn offset_propagation() {
+        let mut scene = Scene::new(100.0, 100.0);
+        let parent = scene.add_area(area(10.0, 20.0, None));
+        let child = scene.add_area(area(1.0, 2.0, Some(parent)));
+        let grandchild = scene.add_area(UiArea {
+            color: [1.0, 1.0, 1.0, 0.5],
+            ..area(3.0, 4.0, Some(child))
+        });
+        scene.update();
+        assert_eq!(scene.children(parent), [child]);
+        assert_eq!(scene.area_rect(child), Some((11.0, 21.0, 22.0, 32.0)));
+        assert_eq!(scene.area_rect(grandchild), Some((14.0, 24.0, 26.0, 36.0)));
+
+        // Moving the parent only resolves its subtree again
+        let other = scene.add_area(area(0.0, 0.0, None));
+        scene.update();
+        let parent_area = scene.area_mut(parent).unwrap();
+        parent_area.x_min += 5.0;
+        parent_area.x_max += 5.0;
+        parent_area.enabled = false;
+        parent_area.color[3] = 0.5;
+        let changes = scene.update();
+        assert_eq!(changes.dirty, [parent, child, grandchild]);
+        assert!(!changes.structure);
+        assert_eq!(scene.area_rect(grandchild), Some((19.0, 29.0, 26.0, 36.0)));
+        let resolved = scene.internal_area(grandchild).unwrap().resolved;
+        assert!(!resolved.enabled);
+        assert_eq!(resolved.opacity, 0.25);
+        assert!(scene.internal_area(other).unwrap().resolved.enabled);
+    }
+
+    #[test]
+    fn cycle_rejected() {
+        let mut scene = Scene::new(100.0, 100.0);
+        let parent = scene.add_area(area(10.0, 10.0, None));
+        let child = scene.add_area(area(1.0, 1.0, Some(parent)));
+        scene.update();
+
+        // The area itself and its own child are ignored like a missing parent
+        scene.area_mut(parent).unwrap().parent = Some(child);
+        scene.update();
+        assert_eq!(scene.area_rect(parent), Some((10.0, 20.0, 10.0, 20.0)));
+        assert_eq!(scene.children(child), []);
+        scene.area_mut(parent).unwrap().parent = Some(parent);
+        scene.update();
+        assert_eq!(scene.area_rect(parent), Some((10.0, 20.0, 10.0, 20.0)));
+
+        // Once the cycle is gone, the parent is applied the next time the area changes
+        scene.area_mut(child).unwrap().parent = None;
+        scene.update();
+        scene.area_mut(parent).unwrap().parent = Some(child);
+        scene.update();
+        assert_eq!(scene.children(child), [parent]);
+        assert_eq!(scene.area_rect(parent), Some((11.0, 21.0, 11.0, 21.0)));
+    }
+
+    #[test]
+    fn removal() {
+        let mut scene = Scene::new(100.0, 100.0);
+        let root = scene.add_area(area(0.0, 0.0, None));
+        let parent = scene.add_area(area(10.0, 10.0, Some(root)));
+        let child = scene.add_area(area(1.0, 1.0, Some(parent)));
+        let grandchild = scene.add_area(area(1.0, 1.0, Some(child)));
+        let sibling = scene.add_area(area(20.0, 20.0, Some(root)));
+        scene.update();
+        assert_eq!(scene.children(root), [parent, sibling]);
+
+        scene.remove_area(parent);
+        // Removed in the next update
+        assert!(scene.area(child).is_some());
+        let changes = scene.update();
+        assert!(changes.structure);
+        for handle in [parent, child, grandchild] {
+            assert!(scene.area(handle).is_none());
+            assert_eq!(scene.area_rect(handle), None);
+        }
+        assert_eq!(scene.children(root), [sibling]);
+        assert_eq!(scene.areas().count(), 2);
+
+        // Areas moved to another parent first survive
+        let parent = scene.add_area(area(10.0, 10.0, None));
+        let child = scene.add_area(area(1.0, 1.0, Some(parent)));
+        scene.update();
+        scene.area_mut(child).unwrap().parent = Some(root);
+        scene.remove_area(parent);
+        scene.update();
+        assert_eq!(scene.area_rect(child), Some((1.0, 11.0, 1.0, 11.0)));
+    }
+}
@@ -1,6 +1,7 @@
 // COMPUTE PIPELINE
 struct Uniform {
-    current_frame: u32,
+    // Animation clock in milliseconds
+    time: u32,
     window_width: f32,
     window_height: f32,
 }
@@ -21,5 +22,16 @@ var<uniform> cursor_position: vec2<u32>;
 @compute
 @workgroup_size(1)
 fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
-    result[0] = textureLoad(texture, vec2<u32>(cursor_position.x, cursor_position.y), 0)[0];
+    // The cursor might be outside of the window or the virtual canvas
+    let size = textureDimensions(texture);
+    if cursor_position.x >= size.x || cursor_position.y >= size.y {
+        result[0] = 0u;
+        return;
+    }
+    // Area id, position inside the area and animation frame
+    let texel = textureLoad(texture, vec2<u32>(cursor_position.x, cursor_position.y), 0);
+    result[0] = texel[0];
+    result[1] = texel[1];
+    result[2] = texel[2];
+    result[3] = texel[3];
 }
\ No newline at end of file
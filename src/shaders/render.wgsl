@@ -1,6 +1,15 @@
 struct OffsetTableEntry {
     offset: u32,
     size: u32,
+    width: f32,
+    height: f32,
+    slice_left: f32,
+    slice_right: f32,
+    slice_top: f32,
+    slice_bottom: f32,
+    slice_mode: u32,
+    frame_duration: u32,
+    playback: u32,
 };
 
 struct AllocationTableEntry {
@@ -12,7 +21,8 @@ struct AllocationTableEntry {
 }
 
 struct Uniform {
-    current_frame: u32,
+    // Animation clock in milliseconds
+    time: u32,
     window_width: f32,
     window_height: f32,
 }
@@ -28,12 +38,24 @@ struct InstanceInput {
     @location(4) y_min: f32,
     @location(5) y_max: f32,
     @location(6) area_id: u32,
+    @location(7) color: vec4<f32>,
+    @location(8) animation_mode: u32,
+    @location(9) animation_value: u32,
+    @location(10) clip: vec4<f32>,
+    @location(11) pick_threshold: f32,
 }
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
-    @location(0) tex_coords: vec2<f32>,
-    @location(1) sprite_index: u32,
+    // Position inside the area in pixels
+    @location(0) local_position: vec2<f32>,
+    @location(1) area_size: vec2<f32>,
+    @location(2) allocation_min: vec2<f32>,
+    @location(3) allocation_max: vec2<f32>,
+    @location(4) atlas_index: u32,
+    @location(5) sprite_index: u32,
+    @location(6) color: vec4<f32>,
+    @location(7) clip: vec4<f32>,
 }
 
 @group(0) @binding(0)
@@ -45,6 +67,54 @@ var<storage, read> allocation_table: array<AllocationTableEntry>;
 @group(1) @binding(0)
 var<uniform> uniform_data: Uniform;
 
+// Computes the frame of an animated sprite that is shown after the given amount of milliseconds
+fn animation_frame(sprite: OffsetTableEntry, elapsed: u32) -> u32 {
+    let frames = sprite.size;
+    let step = elapsed / max(sprite.frame_duration, 1u);
+    switch sprite.playback {
+        // Once
+        case 1u: {
+            return min(step, frames - 1u);
+        }
+        // Ping-pong
+        case 2u: {
+            if frames < 2u {
+                return 0u;
+            }
+            let period = 2u * frames - 2u;
+            let t = step % period;
+            return select(period - t, t, t < frames);
+        }
+        // Reverse
+        case 3u: {
+            return frames - 1u - step % frames;
+        }
+        // Loop
+        default: {
+            return step % frames;
+        }
+    }
+}
+
+// Computes the frame an area shows, based on its own animation state
+fn area_frame(sprite: OffsetTableEntry, instance: InstanceInput) -> u32 {
+    switch instance.animation_mode {
+        // Paused
+        case 1u: {
+            return animation_frame(sprite, instance.animation_value);
+        }
+        // Fixed frame
+        case 2u: {
+            return min(instance.animation_value, sprite.size - 1u);
+        }
+        // Playing
+        default: {
+            let elapsed = select(0u, uniform_data.time - instance.animation_value, uniform_data.time >= instance.animation_value);
+            return animation_frame(sprite, elapsed);
+        }
+    }
+}
+
 @vertex
 fn vs_main(
     @builtin(vertex_index) vertex_index: u32,
@@ -53,31 +123,72 @@ fn vs_main(
 ) -> VertexOutput {
     var out: VertexOutput;
     let offset_table_entry = offset_table[instance.sprite_index];
-    let allocation = allocation_table[offset_table_entry.offset + uniform_data.current_frame % offset_table_entry.size];
+    let allocation = allocation_table[offset_table_entry.offset + area_frame(offset_table_entry, instance)];
     switch vertex_index {
         case 0u: {
             out.clip_position = vec4<f32>(instance.x_min, instance.y_min, 0.0, 1.0);
-            out.tex_coords = vec2<f32>(allocation.x_min, allocation.y_min);
         }
         case 1u: {
             out.clip_position = vec4<f32>(instance.x_max, instance.y_min, 0.0, 1.0);
-            out.tex_coords = vec2<f32>(allocation.x_max, allocation.y_min);
         }
         case 2u: {
             out.clip_position = vec4<f32>(instance.x_max, instance.y_max, 0.0, 1.0);
-            out.tex_coords = vec2<f32>(allocation.x_max, allocation.y_max);
         }
         default: {
             out.clip_position = vec4<f32>(instance.x_min, instance.y_max, 0.0, 1.0);
-            out.tex_coords = vec2<f32>(allocation.x_min, allocation.y_max);
         }
     }
+    out.local_position = out.clip_position.xy - vec2<f32>(instance.x_min, instance.y_min);
+    out.area_size = vec2<f32>(instance.x_max - instance.x_min, instance.y_max - instance.y_min);
+    out.allocation_min = vec2<f32>(allocation.x_min, allocation.y_min);
+    out.allocation_max = vec2<f32>(allocation.x_max, allocation.y_max);
+    out.atlas_index = allocation.atlas_index;
     out.clip_position.x = out.clip_position.x / f32(uniform_data.window_width) * 2.0 - 1.0;
     out.clip_position.y = 1.0 - out.clip_position.y / f32(uniform_data.window_height) * 2.0;
     out.sprite_index = instance.sprite_index;
+    out.color = instance.color;
+    out.clip = instance.clip;
     return out;
 }
 
+// Whether a fragment lies outside of the clip rect (x_min, x_max, y_min, y_max) of its area
+fn clipped(position: vec4<f32>, clip: vec4<f32>) -> bool {
+    return position.x < clip.x || position.x > clip.y || position.y < clip.z || position.y > clip.w;
+}
+
+// Maps a position inside the area to a position inside the sprite along one axis.
+// Regular sprites have no insets and are simply stretched, nine-slice sprites keep their borders at native size.
+fn slice_axis(position: f32, area_size: f32, sprite_size: f32, inset_min: f32, inset_max: f32, tile: bool) -> f32 {
+    // Shrink the borders if the area is too small to fit both of them
+    let scale = min(1.0, area_size / max(inset_min + inset_max, 0.0001));
+    let border_min = inset_min * scale;
+    let border_max = inset_max * scale;
+    if position < border_min {
+        return position / scale;
+    }
+    if position >= area_size - border_max {
+        return sprite_size - (area_size - position) / scale;
+    }
+    let center = max(sprite_size - inset_min - inset_max, 0.0001);
+    if tile {
+        return inset_min + (position - border_min) % center;
+    }
+    return inset_min + (position - border_min) / (area_size - border_min - border_max) * center;
+}
+
+fn sprite_tex_coords(in: VertexOutput) -> vec2<f32> {
+    let sprite = offset_table[in.sprite_index];
+    let sprite_size = vec2<f32>(sprite.width, sprite.height);
+    let tile = sprite.slice_mode == 1u;
+    let position = vec2<f32>(
+        slice_axis(in.local_position.x, in.area_size.x, sprite.width, sprite.slice_left, sprite.slice_right, tile),
+        slice_axis(in.local_position.y, in.area_size.y, sprite.height, sprite.slice_top, sprite.slice_bottom, tile),
+    );
+    // Stay inside of the allocation, so neighbouring sprites never bleed in
+    let clamped = clamp(position, vec2<f32>(0.0), sprite_size - 0.001);
+    return in.allocation_min + clamped / sprite_size * (in.allocation_max - in.allocation_min);
+}
+
 // Fragment shader
 @group(0) @binding(2)
 var t_diffuse: texture_2d_array<f32>;
@@ -86,5 +197,9 @@ var s_diffuse: sampler;
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(t_diffuse, s_diffuse, in.tex_coords, in.sprite_index);
+    let sample = textureSample(t_diffuse, s_diffuse, sprite_tex_coords(in), in.atlas_index);
+    if clipped(in.clip_position, in.clip) {
+        discard;
+    }
+    return sample * in.color;
 }
\ No newline at end of file
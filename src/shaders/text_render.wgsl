@@ -1,25 +1,134 @@
 // Vertex shader
 struct Uniform {
-    current_frame: u32,
+    // Animation clock in milliseconds
+    time: u32,
     window_width: f32,
     window_height: f32,
 }
 @group(1) @binding(0)
 var<uniform> uniform_data: Uniform;
 
+struct OffsetTableEntry {
+    offset: u32,
+    size: u32,
+    width: f32,
+    height: f32,
+    slice_left: f32,
+    slice_right: f32,
+    slice_top: f32,
+    slice_bottom: f32,
+    slice_mode: u32,
+    frame_duration: u32,
+    playback: u32,
+};
+
+struct AllocationTableEntry {
+    x_min: f32,
+    x_max: f32,
+    y_min: f32,
+    y_max: f32,
+    atlas_index: u32,
+}
+
+// The sprite atlas, for inline sprites
+@group(2) @binding(0)
+var<storage, read> offset_table: array<OffsetTableEntry>;
+@group(2) @binding(1)
+var<storage, read> allocation_table: array<AllocationTableEntry>;
+@group(2) @binding(2)
+var t_sprites: texture_2d_array<f32>;
+
 struct VertexInput {
     @location(0) pos_x: f32,
     @location(1) pos_y: f32,
-    @location(2) tex_x: f32,
-    @location(3) tex_y: f32,
+    // Position relative to the top left corner of the glyph, in texels
+    @location(2) local_x: f32,
+    @location(3) local_y: f32,
     @location(4) atlas_index: u32,
     @location(5) area_id: u32,
+    @location(6) clip: vec4<f32>,
+    // Top left corner of the area
+    @location(7) origin: vec2<f32>,
+    // x, y, width, height of the glyph in the atlas, an x of u32::MAX marks a solid rectangle for underlines
+    @location(8) glyph: vec4<u32>,
+    @location(9) color: vec4<f32>,
+    // The glyph is grown by this many texels in every direction, for outlines
+    @location(10) dilation: u32,
+    // Mode, phase and amplitude of the text effect
+    @location(11) effect: vec3<u32>,
+    // Index of the inline sprite in the offset table plus one, 0 for glyphs and underlines.
+    // The local position and the size in glyph are in texels of the sprite then.
+    @location(12) sprite: u32,
 }
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
-    @location(0) tex_coords: vec2<f32>,
+    @location(0) local: vec2<f32>,
     @location(1) atlas_index: u32,
+    @location(2) clip: vec4<f32>,
+    @location(3) glyph: vec4<u32>,
+    @location(4) color: vec4<f32>,
+    @location(5) dilation: u32,
+    @location(6) sprite: u32,
+    // Top left corner of the current frame of an inline sprite in the atlas, in texture coordinates
+    @location(7) @interpolate(flat) sprite_origin: vec2<f32>,
+}
+
+// Computes the frame of an animated sprite that is shown after the given amount of milliseconds, see render.wgsl
+fn animation_frame(sprite: OffsetTableEntry, elapsed: u32) -> u32 {
+    let frames = sprite.size;
+    let step = elapsed / max(sprite.frame_duration, 1u);
+    switch sprite.playback {
+        // Once
+        case 1u: {
+            return min(step, frames - 1u);
+        }
+        // Ping-pong
+        case 2u: {
+            if frames < 2u {
+                return 0u;
+            }
+            let period = 2u * frames - 2u;
+            let t = step % period;
+            return select(period - t, t, t < frames);
+        }
+        // Reverse
+        case 3u: {
+            return frames - 1u - step % frames;
+        }
+        // Loop
+        default: {
+            return step % frames;
+        }
+    }
+}
+
+// An integer hash with good avalanche, see hash in text.rs
+fn hash(value: u32) -> u32 {
+    var x = value;
+    x ^= x >> 16u;
+    x *= 0x7feb352du;
+    x ^= x >> 15u;
+    x *= 0x846ca68bu;
+    return x ^ (x >> 16u);
+}
+
+// Offset in pixels of a glyph with a text effect, integer math like effect_offset in text.rs
+fn effect_offset(effect: vec3<u32>, time: u32) -> vec2<f32> {
+    let amplitude = i32(effect.z);
+    // Wave: a triangle wave over 8 steps of 100ms, shifted by one step per glyph
+    if effect.x == 1u {
+        let step = (time / 100u + effect.y) % 8u;
+        return vec2<f32>(0.0, f32((abs(i32(step) - 4) - 2) * amplitude));
+    }
+    // Shake: a new random offset of up to one amplitude every 50ms
+    if effect.x == 2u {
+        let hash = hash(effect.y ^ ((time / 50u) * 0x9e3779b9u));
+        let x = i32(hash % 3u) - 1;
+        let y = i32(hash / 3u % 3u) - 1;
+        return vec2<f32>(f32(x * amplitude), f32(y * amplitude));
+    }
+    return vec2<f32>(0.0, 0.0);
 }
 
 @vertex
@@ -27,26 +136,85 @@ fn vs_main(
     input: VertexInput,
 ) -> VertexOutput {
     var out: VertexOutput;
-    out.clip_position.x = input.pos_x / f32(uniform_data.window_width) * 2.0 - 1.0;
-    out.clip_position.y = 1.0 - input.pos_y / f32(uniform_data.window_height) * 2.0;
+    let offset = effect_offset(input.effect, uniform_data.time);
+    out.clip_position.x = (input.pos_x + offset.x) / f32(uniform_data.window_width) * 2.0 - 1.0;
+    out.clip_position.y = 1.0 - (input.pos_y + offset.y) / f32(uniform_data.window_height) * 2.0;
     out.clip_position.z = 0.0;
     out.clip_position.w = 1.0;
-    out.tex_coords = vec2<f32>(input.tex_x, input.tex_y);
+    out.local = vec2<f32>(input.local_x, input.local_y);
     out.atlas_index = input.atlas_index;
+    out.clip = input.clip;
+    out.glyph = input.glyph;
+    out.color = input.color;
+    out.dilation = input.dilation;
+    out.sprite = input.sprite;
+    if input.sprite != 0u {
+        let sprite = offset_table[input.sprite - 1u];
+        let allocation = allocation_table[sprite.offset + animation_frame(sprite, uniform_data.time)];
+        out.atlas_index = allocation.atlas_index;
+        out.sprite_origin = vec2<f32>(allocation.x_min, allocation.y_min);
+    }
     return out;
 }
 
+// Whether a fragment lies outside of the clip rect (x_min, x_max, y_min, y_max) of its area
+fn clipped(position: vec4<f32>, clip: vec4<f32>) -> bool {
+    return position.x < clip.x || position.x > clip.y || position.y < clip.z || position.y > clip.w;
+}
+
 // Fragment shader
 @group(0) @binding(0)
 var t_diffuse: texture_2d_array<f32>;
-@group(0) @binding(1)
-var s_diffuse: sampler;
+
+// Coverage of a texel of the glyph, 0 outside of the glyph so neighbors in the atlas don't bleed in
+fn texel_coverage(glyph: vec4<u32>, atlas_index: u32, texel: vec2<i32>) -> f32 {
+    if texel.x < 0 || texel.y < 0 || texel.x >= i32(glyph.z) || texel.y >= i32(glyph.w) {
+        return 0.0;
+    }
+    if glyph.x == 0xffffffffu {
+        return 1.0;
+    }
+    return textureLoad(t_diffuse, vec2<i32>(glyph.xy) + texel, atlas_index, 0).x;
+}
+
+// Coverage of the glyph grown by dilation texels: the highest coverage in the square around the texel
+fn coverage(glyph: vec4<u32>, atlas_index: u32, local: vec2<f32>, dilation: u32) -> f32 {
+    let texel = vec2<i32>(floor(local));
+    let radius = i32(dilation);
+    var result = 0.0;
+    for (var y = -radius; y <= radius; y++) {
+        for (var x = -radius; x <= radius; x++) {
+            result = max(result, texel_coverage(glyph, atlas_index, texel + vec2<i32>(x, y)));
+        }
+    }
+    return result;
+}
+
+// Texel of the current frame of an inline sprite, its local position is in texels of the sprite
+fn sprite_texel(in: VertexOutput) -> vec4<f32> {
+    // The sprite is scaled, the nudge keeps texel borders that fall onto pixel centers on the same side
+    // as in the software renderer
+    let texel = vec2<i32>(floor(in.local + 0.001));
+    if texel.x < 0 || texel.y < 0 || texel.x >= i32(in.glyph.z) || texel.y >= i32(in.glyph.w) {
+        return vec4<f32>(0.0);
+    }
+    let origin = vec2<i32>(round(in.sprite_origin * vec2<f32>(textureDimensions(t_sprites))));
+    return textureLoad(t_sprites, origin + texel, in.atlas_index, 0);
+}
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    let sample = textureSample(t_diffuse, s_diffuse, in.tex_coords, in.atlas_index);
-    if sample.x < 0.00001 {
+    if in.sprite != 0u {
+        let sample = sprite_texel(in);
+        if sample.a < 0.00001 || clipped(in.clip_position, in.clip) {
+            discard;
+        }
+        // Tinted with the color of the span
+        return vec4<f32>(sample.rgb * in.color.rgb, sample.a * in.color.a);
+    }
+    let coverage = coverage(in.glyph, in.atlas_index, in.local, in.dilation);
+    if coverage < 0.00001 || clipped(in.clip_position, in.clip) {
         discard;
     }
-    return vec4<f32>(1.0, 1.0, 1.0, sample.x);
-}
\ No newline at end of file
+    return vec4<f32>(in.color.rgb, in.color.a * coverage);
+}
@@ -0,0 +1,621 @@
+// A software renderer for environments without a GPU, e.g. golden image tests on a CI server or tools.
+// It shares the scene with Stgi and samples sprites and glyphs like the shaders do, so it produces the same pixels.
+
+use std::time::Duration;
+
+use image::{Rgba, RgbaImage};
+
+use super::{
+    builder::StgiBuilder,
+    cpu::{self, CpuGlyphs, CpuSprites},
+    scene::Scene,
+    sprite::Sprite,
+    text::FontId,
+    Hit, SpriteId, UiArea, UiAreaHandle, ZOrder,
+};
+
+/// The result of SoftwareStgi::render
+#[derive(Debug, Clone)]
+pub struct SoftwareFrame {
+    /// The UI blended onto a transparent background, so the colors of partly transparent pixels are premultiplied
+    pub image: RgbaImage,
+    // Row major, like the cursor picking texture of Stgi
+    area_ids: Vec<Option<UiAreaHandle>>,
+}
+
+impl SoftwareFrame {
+    /// The area at the given pixel, picked like Stgi picks the area under the cursor.
+    /// None outside of the image.
+    pub fn area_at(&self, x: u32, y: u32) -> Option<UiAreaHandle> {
+        if x >= self.image.width() || y >= self.image.height() {
+            return None;
+        }
+        self.area_ids[(y * self.image.width() + x) as usize]
+    }
+
+    /// The area of every pixel, row major
+    pub fn area_ids(&self) -> &[Option<UiAreaHandle>] {
+        &self.area_ids
+    }
+}
+
+/// Renders the same areas, sprites and text as Stgi on the CPU, no wgpu device needed.
+/// Blends in linear space like Stgi does on an sRGB surface. Created with StgiBuilder::build_software.
+pub struct SoftwareStgi<S: SpriteId, F: FontId> {
+    scene: Scene<S, F>,
+    sprites: CpuSprites<S>,
+    glyphs: CpuGlyphs<F>,
+    // Only with a virtual resolution
+    virtual_resolution: bool,
+}
+
+impl<S: SpriteId, F: FontId> SoftwareStgi<S, F> {
+    pub(crate) fn new(
+        scene: Scene<S, F>,
+        sprites: CpuSprites<S>,
+        glyphs: CpuGlyphs<F>,
+        virtual_resolution: bool,
+    ) -> Self {
+        Self {
+            scene,
+            sprites,
+            glyphs,
+            virtual_resolution,
+        }
+    }
+
+    /// Same as Stgi::builder
+    pub fn builder() -> StgiBuilder<S, F> {
+        StgiBuilder::new()
+    }
+
+    /// Adds a new UIArea, see Stgi::add_area
+    pub fn add_area(&mut self, area: UiArea<S, F>) -> UiAreaHandle {
+        self.scene.add_area(area)
+    }
+
+    /// Gets a reference to a UiArea by its handle
+    pub fn area(&self, handle: UiAreaHandle) -> Option<&UiArea<S, F>> {
+        self.scene.area(handle)
+    }
+
+    /// Removes the area in the next update, together with all of its children and their children.
+    /// The handles of all removed areas become invalid, to keep the children move them to another parent first.
+    pub fn remove_area(&mut self, area: UiAreaHandle) {
+        self.scene.remove_area(area);
+    }
+
+    /// Removes all areas
+    pub fn clear(&mut self) {
+        self.scene.clear();
+    }
+
+    /// Gets a mutable reference to a UiArea by its handle, see Stgi::area_mut
+    pub fn area_mut(&mut self, handle: UiAreaHandle) -> Option<&mut UiArea<S, F>> {
+        self.scene.area_mut(handle)
+    }
+
+    /// Gets the children of a UiArea, as of the last render
+    pub fn children(&self, handle: UiAreaHandle) -> &[UiAreaHandle] {
+        self.scene.children(handle)
+    }
+
+    /// Gets the position of a UiArea in pixels (x_min, x_max, y_min, y_max), as of the last render
+    pub fn area_rect(&self, handle: UiAreaHandle) -> Option<(f32, f32, f32, f32)> {
+        self.scene.area_rect(handle)
+    }
+
+    /// Advances the animation clock, see Stgi::advance_animations
+    pub fn advance_animations(&mut self, delta: Duration) {
+        self.scene.advance_animations(delta);
+    }
+
+    /// The current time of the animation clock
+    pub fn animation_time(&self) -> Duration {
+        self.scene.animation_time()
+    }
+
+    /// Restarts the animation of an area from its first frame.
+    pub fn restart_animation(&mut self, handle: UiAreaHandle) {
+        self.scene.restart_animation(handle);
+    }
+
+    /// Freezes the animation of an area at its current frame.
+    pub fn pause_animation(&mut self, handle: UiAreaHandle) {
+        self.scene.pause_animation(handle);
+    }
+
+    /// Continues a paused animation from where it was paused.
+    pub fn resume_animation(&mut self, handle: UiAreaHandle) {
+        self.scene.resume_animation(handle);
+    }
+
+    /// Shows a fixed frame of the animation of an area, until the animation is restarted.
+    pub fn seek_animation(&mut self, handle: UiAreaHandle, frame: u32) {
+        self.scene.seek_animation(handle, frame);
+    }
+
+    /// Registers a new sprite, see Stgi::add_sprite.
+    /// Panics if the sprite is already registered, use replace_sprite instead.
+    pub fn add_sprite(&mut self, sprite_id: S, sprite: Sprite) {
+        assert!(
+            !self.sprites.contains(&sprite_id),
+            "Sprite: {:?} already registered",
+            sprite_id
+        );
+        sprite.validate();
+        self.sprites.insert(sprite_id.clone(), &sprite);
+        self.scene.mark_sprite_dirty(&sprite_id);
+    }
+
+    /// Replaces an already registered sprite, all areas using it are updated.
+    /// Panics if the sprite is not registered.
+    pub fn replace_sprite(&mut self, sprite_id: S, sprite: Sprite) {
+        sprite.validate();
+        assert!(
+            self.sprites.contains(&sprite_id),
+            "Sprite: {:?} not registered",
+            sprite_id
+        );
+        self.sprites.insert(sprite_id.clone(), &sprite);
+        self.scene.mark_sprite_dirty(&sprite_id);
+    }
+
+    /// Removes a sprite, areas using it are rendered without a sprite. Returns false if the sprite was not registered.
+    pub fn remove_sprite(&mut self, sprite_id: &S) -> bool {
+        if !self.sprites.remove(sprite_id) {
+            return false;
+        }
+        self.scene.mark_sprite_dirty(sprite_id);
+        true
+    }
+
+    /// Whether a sprite with the given id is registered
+    pub fn has_sprite(&self, sprite_id: &S) -> bool {
+        self.sprites.contains(sprite_id)
+    }
+
+    /// Changes the size of the rendered image. Areas with a placement are laid out again.
+    /// Ignored with a virtual resolution, the image always has the virtual resolution.
+    pub fn resize(&mut self, new_width: u32, new_height: u32) {
+        if self.virtual_resolution {
+            return;
+        }
+        self.scene.resize(new_width as f32, new_height as f32);
+    }
+
+    /// Returns the area at the given pixel, picked like in the frames returned by render.
+    /// Uses the areas as of the last render.
+    pub fn hit_test(&self, x: u32, y: u32) -> Option<UiAreaHandle> {
+        self.hit_at(x, y).map(|hit| hit.area)
+    }
+
+    /// Like hit_test, but also tells where inside of the area the pixel is, see Stgi::hit_at
+    pub fn hit_at(&self, x: u32, y: u32) -> Option<Hit> {
+        cpu::areas_at(&self.scene, &self.sprites, &self.glyphs, x, y)
+            .next()
+            .map(|(hit, _)| hit)
+    }
+
+    /// Returns every area at the given pixel from top to bottom, together with its z order, see Stgi::areas_at
+    pub fn areas_at(&self, x: u32, y: u32) -> Vec<(UiAreaHandle, ZOrder)> {
+        cpu::areas_at(&self.scene, &self.sprites, &self.glyphs, x, y)
+            .map(|(hit, z)| (hit.area, z))
+            .collect()
+    }
+
+    /// Updates the areas and renders them into an image, together with the area of every pixel.
+    pub fn render(&mut self) -> SoftwareFrame {
+        self.scene.update();
+        let (width, height) = self.scene.window_size();
+        let (width, height) = (width as u32, height as u32);
+        let time = self.scene.animation_time().as_millis() as u32;
+        // Linear colors, encoded at the end like an sRGB surface does
+        let mut colors = vec![[0.0f32; 4]; (width * height) as usize];
+        let mut area_ids = vec![None; (width * height) as usize];
+
+        for (handle, area) in self.scene.areas() {
+            let resolved = &area.resolved;
+            if !resolved.enabled {
+                continue;
+            }
+            let [r, g, b, _] = area.area.color;
+            let color = [r, g, b, resolved.opacity];
+            let has_sprite = area
+                .sprite()
+                .is_some_and(|sprite| self.sprites.contains(sprite));
+            let (x_range, y_range) = match has_sprite {
+                true => (
+                    pixel_range(resolved.x_min, resolved.x_max, width),
+                    pixel_range(resolved.y_min, resolved.y_max, height),
+                ),
+                false => (0..0, 0..0),
+            };
+            let pickable = area.area.pickable;
+            let text_pickable =
+                pickable && area.area.text.as_ref().is_some_and(|text| text.pickable);
+            let pick_threshold = self.sprites.picking(area).threshold();
+            for y in y_range {
+                for x in x_range.clone() {
+                    let (center_x, center_y) = (x as f32 + 0.5, y as f32 + 0.5);
+                    if resolved.clipped(center_x, center_y) {
+                        continue;
+                    }
+                    let Some(sample) = self.sprites.sample(area, time, center_x, center_y) else {
+                        continue;
+                    };
+                    let index = (y * width + x) as usize;
+                    if pickable && sample[3] as f32 / 255.0 >= pick_threshold {
+                        area_ids[index] = Some(handle);
+                    }
+                    let sample = decode(sample);
+                    blend(
+                        &mut colors[index],
+                        [
+                            sample[0] * color[0],
+                            sample[1] * color[1],
+                            sample[2] * color[2],
+                            sample[3] * color[3],
+                        ],
+                    );
+                }
+            }
+
+            let window = (0..width as i32, 0..height as i32);
+            self.glyphs.for_each_pixel(
+                area,
+                &self.sprites,
+                time,
+                window,
+                |x, y, coverage, color| {
+                    if resolved.clipped(x as f32 + 0.5, y as f32 + 0.5) {
+                        return;
+                    }
+                    let index = (y as u32 * width + x as u32) as usize;
+                    let alpha = color[3] * coverage as f32 / 255.0;
+                    blend(&mut colors[index], [color[0], color[1], color[2], alpha]);
+                    if text_pickable {
+                        area_ids[index] = Some(handle);
+                    }
+                },
+            );
+        }
+
+        let mut image = RgbaImage::new(width, height);
+        for (pixel, color) in image.pixels_mut().zip(&colors) {
+            *pixel = encode(*color);
+        }
+        SoftwareFrame { image, area_ids }
+    }
+}
+
+/// The pixels whose center lies inside of min..max, clamped to 0..size
+fn pixel_range(min: f32, max: f32, size: u32) -> std::ops::Range<u32> {
+    let start = (min - 0.5).ceil().clamp(0.0, size as f32) as u32;
+    let end = (max - 0.5).ceil().clamp(0.0, size as f32) as u32;
+    start..end
+}
+
+/// Alpha blending like BlendState::ALPHA_BLENDING
+fn blend(dst: &mut [f32; 4], src: [f32; 4]) {
+    let alpha = src[3];
+    for channel in 0..3 {
+        dst[channel] = src[channel] * alpha + dst[channel] * (1.0 - alpha);
+    }
+    dst[3] = alpha + dst[3] * (1.0 - alpha);
+}
+
+/// sRGB texel to linear color, alpha stays linear
+pub(crate) fn decode(Rgba([r, g, b, a]): Rgba<u8>) -> [f32; 4] {
+    let to_linear = |c: u8| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    [to_linear(r), to_linear(g), to_linear(b), a as f32 / 255.0]
+}
+
+/// Linear color to sRGB pixel, alpha stays linear
+fn encode([r, g, b, a]: [f32; 4]) -> Rgba<u8> {
+    let to_srgb = |c: f32| {
+        let c = c.clamp(0.0, 1.0);
+        let c = if c <= 0.0031308 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        };
+        (c * 255.0).round() as u8
+    };
+    Rgba([
+        to_srgb(r),
+        to_srgb(g),
+        to_srgb(b),
+        (a.clamp(0.0, 1.0) * 255.0).round() as u8,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+    use crate::{
+        sprite::{NineSlice, NineSliceMode, Picking},
+        ClipRect, Text,
+    };
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum TestSprite {
+        White,
+        Corners,
+        Large,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum TestFont {
+        Pixel,
+        // Never added to the builder
+        Missing,
+    }
+
+    const TRANSPARENT: Rgba<u8> = Rgba([0, 0, 0, 0]);
+
+    /// An 8x8 sprite with a red, green, blue and white 3x3 corner, nine-sliced with insets of 3
+    fn corners() -> RgbaImage {
+        RgbaImage::from_fn(8, 8, |x, y| match (x < 3, x >= 5, y < 3, y >= 5) {
+            (true, _, true, _) => Rgba([255, 0, 0, 255]),
+            (_, true, true, _) => Rgba([0, 255, 0, 255]),
+            (true, _, _, true) => Rgba([0, 0, 255, 255]),
+            (_, true, _, true) => Rgba([255, 255, 255, 255]),
+            _ => TRANSPARENT,
+        })
+    }
+
+    fn software(width: u32, height: u32) -> SoftwareStgi<TestSprite, TestFont> {
+        let mut builder = StgiBuilder::new();
+        builder.add_font(TestFont::Pixel, include_bytes!("../examples/m5x7.ttf"));
+        builder.add_inanimate_sprite(
+            TestSprite::White,
+            RgbaImage::from_pixel(4, 4, Rgba([255, 255, 255, 255])),
+        );
+        builder.add_nine_slice_sprite(
+            TestSprite::Corners,
+            corners(),
+            NineSlice {
+                left: 3,
+                right: 3,
+                top: 3,
+                bottom: 3,
+                mode: NineSliceMode::Stretch,
+            },
+        );
+        builder.build_software(width, height)
+    }
+
+    fn area(x_min: f32, x_max: f32, y_min: f32, y_max: f32) -> UiArea<TestSprite, TestFont> {
+        UiArea {
+            x_min,
+            x_max,
+            y_min,
+            y_max,
+            sprite: Some(TestSprite::White),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tinted_sprite() {
+        let mut stgi = software(16, 16);
+        let handle = stgi.add_area(UiArea {
+            color: [1.0, 0.0, 0.0, 1.0],
+            ..area(2.0, 10.0, 2.0, 10.0)
+        });
+        let frame = stgi.render();
+        assert_eq!(*frame.image.get_pixel(2, 2), Rgba([255, 0, 0, 255]));
+        assert_eq!(*frame.image.get_pixel(9, 9), Rgba([255, 0, 0, 255]));
+        assert_eq!(*frame.image.get_pixel(10, 10), TRANSPARENT);
+        assert_eq!(frame.area_at(5, 5), Some(handle));
+        assert_eq!(frame.area_at(1, 5), None);
+        assert_eq!(frame.area_at(16, 5), None);
+    }
+
+    #[test]
+    fn nine_slice_smaller_than_insets() {
+        let mut stgi = software(8, 8);
+        // The insets add up to 6 pixels, so the corners are scaled down to 2 pixels and nothing else is shown
+        let handle = stgi.add_area(UiArea {
+            sprite: Some(TestSprite::Corners),
+            ..area(0.0, 4.0, 0.0, 4.0)
+        });
+        let frame = stgi.render();
+        for (x, y, color) in [
+            (0, 0, Rgba([255, 0, 0, 255])),
+            (1, 1, Rgba([255, 0, 0, 255])),
+            (3, 0, Rgba([0, 255, 0, 255])),
+            (0, 3, Rgba([0, 0, 255, 255])),
+            (2, 2, Rgba([255, 255, 255, 255])),
+            (3, 3, Rgba([255, 255, 255, 255])),
+        ] {
+            assert_eq!(*frame.image.get_pixel(x, y), color, "pixel ({x}, {y})");
+            assert_eq!(frame.area_at(x, y), Some(handle));
+        }
+        assert_eq!(*frame.image.get_pixel(4, 4), TRANSPARENT);
+    }
+
+    #[test]
+    fn clip_rect() {
+        let mut stgi = software(16, 16);
+        let handle = stgi.add_area(UiArea {
+            clip: Some(ClipRect {
+                x_min: 0.0,
+                x_max: 5.0,
+                y_min: 0.0,
+                y_max: 16.0,
+            }),
+            ..area(0.0, 10.0, 0.0, 10.0)
+        });
+        // The clip rect moves with the parent
+        let child = stgi.add_area(UiArea {
+            parent: Some(handle),
+            clip: Some(ClipRect {
+                x_min: 0.0,
+                x_max: 16.0,
+                y_min: 12.0,
+                y_max: 16.0,
+            }),
+            ..area(2.0, 8.0, 10.0, 16.0)
+        });
+        let frame = stgi.render();
+        assert_eq!(*frame.image.get_pixel(4, 4), Rgba([255, 255, 255, 255]));
+        assert_eq!(frame.area_at(4, 4), Some(handle));
+        assert_eq!(*frame.image.get_pixel(5, 4), TRANSPARENT);
+        assert_eq!(frame.area_at(5, 4), None);
+        assert_eq!(frame.area_at(3, 12), Some(child));
+        assert_eq!(frame.area_at(3, 11), None);
+        assert_eq!(frame.area_at(6, 12), None);
+    }
+
+    #[test]
+    fn parent_opacity() {
+        let mut stgi = software(16, 16);
+        let parent = stgi.add_area(UiArea {
+            sprite: None,
+            color: [1.0, 1.0, 1.0, 0.5],
+            ..area(0.0, 16.0, 0.0, 16.0)
+        });
+        let child = stgi.add_area(UiArea {
+            parent: Some(parent),
+            color: [1.0, 1.0, 1.0, 0.5],
+            ..area(4.0, 8.0, 4.0, 8.0)
+        });
+        let frame = stgi.render();
+        // 0.25 opacity, blended onto transparent black and encoded as sRGB
+        assert_eq!(*frame.image.get_pixel(5, 5), Rgba([137, 137, 137, 64]));
+        assert_eq!(frame.area_at(5, 5), Some(child));
+        assert_eq!(frame.area_at(1, 1), None);
+
+        stgi.area_mut(parent).unwrap().color[3] = 1.0;
+        let frame = stgi.render();
+        assert_eq!(*frame.image.get_pixel(5, 5), Rgba([188, 188, 188, 128]));
+    }
+
+    #[test]
+    fn text_glyph() {
+        let mut stgi = software(32, 16);
+        let handle = stgi.add_area(UiArea {
+            sprite: None,
+            text: Some(Text::new(TestFont::Pixel, 16, "I")),
+            ..area(0.0, 32.0, 0.0, 16.0)
+        });
+        let frame = stgi.render();
+        // m5x7 is a pixel font, so the glyph covers whole pixels: two serifs and a stem
+        let covered: Vec<(u32, u32)> = frame
+            .image
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| pixel[3] > 0)
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        let serif = |y| (13..18).map(move |x| (x, y));
+        let stem = (5..10).map(|y| (15, y));
+        let expected: Vec<(u32, u32)> = serif(4).chain(stem).chain(serif(10)).collect();
+        assert_eq!(covered, expected);
+        assert_eq!(*frame.image.get_pixel(15, 7), Rgba([255, 255, 255, 255]));
+        assert_eq!(frame.area_at(15, 7), Some(handle));
+        assert_eq!(frame.area_at(14, 7), None);
+
+        // Faded with the opacity of the area like a sprite
+        stgi.area_mut(handle).unwrap().color[3] = 0.5;
+        let frame = stgi.render();
+        assert_eq!(*frame.image.get_pixel(15, 7), Rgba([188, 188, 188, 128]));
+        assert_eq!(*frame.image.get_pixel(0, 0), TRANSPARENT);
+        assert_eq!(frame.area_at(0, 0), None);
+    }
+
+    #[test]
+    fn unknown_font() {
+        let mut stgi = software(32, 16);
+        let mut text = Text::new(TestFont::Pixel, 16, "");
+        text.set_markup(
+            "[font=missing]I[/font]I",
+            |_| Some(TestFont::Missing),
+            |_| None,
+        )
+        .unwrap();
+        let handle = stgi.add_area(UiArea {
+            sprite: None,
+            text: Some(text),
+            ..area(0.0, 32.0, 0.0, 16.0)
+        });
+        // Only the I in the known font is drawn
+        let frame = stgi.render();
+        let covered = frame.image.pixels().filter(|pixel| pixel[3] > 0).count();
+        assert_eq!(covered, 15);
+        assert!(frame.area_ids().contains(&Some(handle)));
+    }
+
+    #[test]
+    fn overflowing_text() {
+        let mut stgi = software(32, 16);
+        // Far too low for the glyph, which sticks out above and below
+        let handle = stgi.add_area(UiArea {
+            sprite: None,
+            text: Some(Text::new(TestFont::Pixel, 16, "I")),
+            ..area(0.0, 32.0, 7.0, 8.0)
+        });
+        let frame = stgi.render();
+        let mut outside = 0;
+        for (x, y, pixel) in frame.image.enumerate_pixels() {
+            let expected = (pixel[3] > 0).then_some(handle);
+            assert_eq!(frame.area_at(x, y), expected, "({x}, {y})");
+            assert_eq!(stgi.hit_test(x, y), expected, "({x}, {y})");
+            if expected.is_some() && y != 7 {
+                outside += 1;
+            }
+        }
+        assert!(outside > 0);
+    }
+
+    #[test]
+    fn sprites_added_to_the_atlas() {
+        let mut stgi = software(4, 2);
+        let white = stgi.add_area(area(0.0, 2.0, 0.0, 2.0));
+        let large = stgi.add_area(UiArea {
+            sprite: Some(TestSprite::Large),
+            ..area(2.0, 4.0, 0.0, 2.0)
+        });
+        // Red on the left half and blue on the right half, too large for the initial atlas
+        let sprite = RgbaImage::from_fn(300, 40, |x, _| match x < 150 {
+            true => Rgba([255, 0, 0, 255]),
+            false => Rgba([0, 0, 255, 255]),
+        });
+        stgi.add_sprite(
+            TestSprite::Large,
+            Sprite::Inanimate {
+                sprite,
+                picking: Picking::default(),
+            },
+        );
+        let frame = stgi.render();
+        // Still where it was packed before the atlas grew
+        assert_eq!(*frame.image.get_pixel(0, 0), Rgba([255, 255, 255, 255]));
+        assert_eq!(*frame.image.get_pixel(2, 0), Rgba([255, 0, 0, 255]));
+        assert_eq!(*frame.image.get_pixel(3, 1), Rgba([0, 0, 255, 255]));
+
+        // Packed into the space the old sprite leaves behind
+        stgi.replace_sprite(
+            TestSprite::Large,
+            Sprite::Inanimate {
+                sprite: RgbaImage::from_pixel(300, 40, Rgba([0, 255, 0, 255])),
+                picking: Picking::default(),
+            },
+        );
+        let frame = stgi.render();
+        assert_eq!(*frame.image.get_pixel(0, 0), Rgba([255, 255, 255, 255]));
+        assert_eq!(*frame.image.get_pixel(2, 0), Rgba([0, 255, 0, 255]));
+        assert_eq!(frame.area_at(1, 1), Some(white));
+        assert_eq!(frame.area_at(3, 1), Some(large));
+    }
+}
@@ -0,0 +1,211 @@
+// Sprites can be registered in the StgiBuilder or at runtime through Stgi::add_sprite.
+// Both paths share the same sprite definition, which is then packed into the sprite atlas.
+
+use std::time::Duration;
+
+use image::{GenericImageView, ImageBuffer, Rgba};
+
+use super::SpriteInfo;
+
+/// How the edges and the center of a nine-slice sprite fill the area
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NineSliceMode {
+    #[default]
+    Stretch,
+    Tile,
+}
+
+/// Border insets of a nine-slice sprite in sprite pixels.
+/// The corners keep their native pixel size, the edges and the center are stretched or tiled to fill the area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NineSlice {
+    pub left: u32,
+    pub right: u32,
+    pub top: u32,
+    pub bottom: u32,
+    pub mode: NineSliceMode,
+}
+
+/// How long a frame of an animated sprite is shown if no frame duration is given,
+/// also the step of the deprecated Stgi::next_animation_frame
+pub const DEFAULT_FRAME_DURATION: Duration = Duration::from_millis(100);
+
+/// How an animated sprite steps through its frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Playback {
+    /// Starts over after the last frame
+    #[default]
+    Loop,
+    /// Plays once and then holds the last frame
+    Once,
+    /// Plays forwards and then backwards again
+    PingPong,
+    /// Loops from the last frame to the first
+    Reverse,
+}
+
+/// How cursor picking decides which pixels of a sprite belong to its area, see also UiArea::picking
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Picking {
+    /// Pixels with at least this alpha (0.0 to 1.0) are picked, fainter ones let the cursor through
+    Alpha(f32),
+    /// The whole rectangle of the area is picked, regardless of the alpha of the sprite
+    Rect,
+}
+
+impl Default for Picking {
+    fn default() -> Self {
+        Picking::Alpha(0.05)
+    }
+}
+
+impl Picking {
+    /// Pixels with a lower alpha are not picked
+    pub(crate) fn threshold(self) -> f32 {
+        match self {
+            Picking::Alpha(alpha) => alpha,
+            // No alpha is below zero
+            Picking::Rect => 0.0,
+        }
+    }
+}
+
+/// A sprite that can be registered in the StgiBuilder or at runtime
+#[derive(Debug, Clone)]
+pub enum Sprite {
+    Inanimate {
+        sprite: ImageBuffer<Rgba<u8>, Vec<u8>>,
+        picking: Picking,
+    },
+    /// All frames are laid out horizontally in the sprite sheet, every frame is sprite_width pixels wide.
+    /// Every frame is shown for frame_duration (millisecond precision), see Stgi::advance_animations.
+    Animated {
+        sprite_sheet: ImageBuffer<Rgba<u8>, Vec<u8>>,
+        sprite_width: u32,
+        frame_duration: Duration,
+        playback: Playback,
+        picking: Picking,
+    },
+    /// Used for panels and buttons that can be resized freely
+    NineSlice {
+        sprite: ImageBuffer<Rgba<u8>, Vec<u8>>,
+        slice: NineSlice,
+        picking: Picking,
+    },
+}
+
+impl Sprite {
+    /// Panics if the sprite can not be packed into the atlas
+    pub(crate) fn validate(&self) {
+        match self {
+            Sprite::Inanimate { sprite, .. } => {
+                let (width, height) = sprite.dimensions();
+                assert!(
+                    width > 0 && height > 0,
+                    "Sprite dimensions must be greater than 0"
+                );
+            }
+            Sprite::Animated {
+                sprite_sheet,
+                sprite_width,
+                ..
+            } => {
+                let (sheet_width, height) = sprite_sheet.dimensions();
+                assert!(
+                    sheet_width > 0 && height > 0 && *sprite_width > 0,
+                    "Sprite sheet dimensions and sprite width must be greater than 0"
+                );
+                assert!(
+                    *sprite_width <= sheet_width,
+                    "Sprite width must not exceed the sprite sheet width"
+                );
+            }
+            Sprite::NineSlice { sprite, slice, .. } => {
+                let (width, height) = sprite.dimensions();
+                assert!(
+                    width > 0 && height > 0,
+                    "Sprite dimensions must be greater than 0"
+                );
+                assert!(
+                    slice.left + slice.right <= width && slice.top + slice.bottom <= height,
+                    "Nine-slice insets must fit into the sprite"
+                );
+            }
+        }
+    }
+
+    pub(crate) fn picking(&self) -> Picking {
+        match self {
+            Sprite::Inanimate { picking, .. }
+            | Sprite::Animated { picking, .. }
+            | Sprite::NineSlice { picking, .. } => *picking,
+        }
+    }
+
+    /// Size of a single frame
+    pub(crate) fn frame_size(&self) -> (u32, u32) {
+        match self {
+            Sprite::Animated {
+                sprite_sheet,
+                sprite_width,
+                ..
+            } => (*sprite_width, sprite_sheet.height()),
+            Sprite::Inanimate { sprite, .. } | Sprite::NineSlice { sprite, .. } => {
+                sprite.dimensions()
+            }
+        }
+    }
+
+    /// Copies every frame out of the sprite (sheet)
+    pub(crate) fn frames(&self) -> Vec<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        match self {
+            Sprite::Inanimate { sprite, .. } | Sprite::NineSlice { sprite, .. } => {
+                vec![sprite.clone()]
+            }
+            Sprite::Animated {
+                sprite_sheet,
+                sprite_width,
+                ..
+            } => {
+                let (height, width) = (sprite_sheet.height(), *sprite_width);
+                (0..(sprite_sheet.width() / width))
+                    .map(|frame_index| {
+                        sprite_sheet
+                            .view(frame_index * width, 0, width, height)
+                            .to_image()
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// The offset table entry of this sprite, apart from the offset into the allocation table
+    pub(crate) fn info(&self, size: u32) -> SpriteInfo {
+        let (width, height) = self.frame_size();
+        let slice = match self {
+            Sprite::NineSlice { slice, .. } => *slice,
+            _ => NineSlice::default(),
+        };
+        let (frame_duration, playback) = match self {
+            Sprite::Animated {
+                frame_duration,
+                playback,
+                ..
+            } => (frame_duration.as_millis().max(1) as u32, *playback),
+            _ => (1, Playback::Loop),
+        };
+        SpriteInfo {
+            offset: 0,
+            size,
+            width: width as f32,
+            height: height as f32,
+            slice_left: slice.left as f32,
+            slice_right: slice.right as f32,
+            slice_top: slice.top as f32,
+            slice_bottom: slice.bottom as f32,
+            slice_mode: slice.mode as u32,
+            frame_duration,
+            playback: playback as u32,
+        }
+    }
+}
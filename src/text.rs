@@ -6,35 +6,156 @@
 use ahash::HashMap;
 use bytemuck::{Pod, Zeroable};
 use fontdue::{
-    layout::{
-        CoordinateSystem, HorizontalAlign, Layout, LayoutSettings, TextStyle, VerticalAlign,
-        WrapStyle,
-    },
+    layout::{CoordinateSystem, Layout, LayoutSettings, LinePosition, TextStyle, WrapStyle},
     Font,
 };
 use guillotiere::{size2, Rectangle, SimpleAtlasAllocator};
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::ops::Range;
 use wgpu::*;
 
-use super::{SpriteId, UiArea, UiAreaHandle};
+use super::{
+    atlas::SpriteAtlas,
+    scene::{InternalUiArea, ResolvedArea},
+    ClipRect, SpriteId, Text, UiAreaHandle,
+};
 
 pub trait FontId: Copy + Eq + Debug + Hash {}
 impl<T> FontId for T where T: Copy + Eq + Debug + Hash {}
 
+/// Horizontal alignment of every line of a Text inside its area
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HorizontalAlign {
+    Left,
+    #[default]
+    Center,
+    Right,
+}
+
+/// Vertical alignment of all lines of a Text inside its area
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerticalAlign {
+    Top,
+    #[default]
+    Middle,
+    Bottom,
+}
+
+/// How lines that don't fit into the area are broken up, line breaks in the text always start a new line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Wrap {
+    /// Between words, a word that is longer than the line is broken up between its letters
+    #[default]
+    Word,
+    /// Between any two letters
+    Letter,
+}
+
+/// Animation of the glyphs of a Text or TextSpan, timed with the animation clock.
+/// Glyphs move by whole pixels, scaled with the font size, and underlines stay in place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextEffect {
+    #[default]
+    None,
+    /// The glyphs bob up and down one after another
+    Wave,
+    /// Every glyph jitters around its position on its own
+    Shake,
+}
+
+impl TextEffect {
+    /// (mode, phase, amplitude) of a glyph, see effect_offset
+    pub(crate) fn to_vertex_data(self, index: usize, size: u16) -> [u32; 3] {
+        let mode = match self {
+            TextEffect::None => 0,
+            TextEffect::Wave => 1,
+            TextEffect::Shake => 2,
+        };
+        [mode, index as u32, (size as u32 / 16).max(1)]
+    }
+}
+
+/// Offset in pixels of a glyph with the given effect vertex data at a time in milliseconds.
+/// Only integer math, so the vertex shaders compute the very same offset, see effect_offset in text_render.wgsl.
+pub(crate) fn effect_offset([mode, phase, amplitude]: [u32; 3], time: u32) -> (f32, f32) {
+    let amplitude = amplitude as i32;
+    match mode {
+        1 => {
+            // A triangle wave over 8 steps of 100ms, shifted by one step per glyph
+            let step = (time / 100).wrapping_add(phase) % 8;
+            (0.0, ((step as i32 - 4).abs() - 2) as f32 * amplitude as f32)
+        }
+        2 => {
+            // A new random offset of up to one amplitude every 50ms
+            let hash = hash(phase ^ (time / 50).wrapping_mul(0x9e37_79b9));
+            let x = (hash % 3) as i32 - 1;
+            let y = (hash / 3 % 3) as i32 - 1;
+            ((x * amplitude) as f32, (y * amplitude) as f32)
+        }
+        _ => (0.0, 0.0),
+    }
+}
+
+/// An integer hash with good avalanche, see hash in text_render.wgsl
+fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^ (x >> 16)
+}
+
+/// Space in pixels between the border of an area and its text
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Padding {
+    pub left: f32,
+    pub right: f32,
+    pub top: f32,
+    pub bottom: f32,
+}
+
+impl Padding {
+    /// The same padding on every side
+    pub const fn all(padding: f32) -> Self {
+        Self {
+            left: padding,
+            right: padding,
+            top: padding,
+            bottom: padding,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
 struct GlyphVertex {
     pos_x: f32,
     pos_y: f32,
-    tex_x: f32,
-    tex_y: f32,
+    // Position relative to the top left corner of the glyph, in texels
+    local_x: f32,
+    local_y: f32,
     atlas_index: u32,
     area_id: u32,
+    // x_min, x_max, y_min, y_max
+    clip: [f32; 4],
+    // Top left corner of the area, for the position inside the area in cursor picking
+    origin: [f32; 2],
+    // x, y, width, height of the glyph in the atlas, in texels. An x of u32::MAX marks a solid rectangle.
+    glyph: [u32; 4],
+    // The coverage of the glyph is multiplied into the alpha
+    color: [f32; 4],
+    // The glyph is grown by this many texels in every direction, for outlines
+    dilation: u32,
+    // Mode, phase and amplitude of the TextEffect
+    effect: [u32; 3],
+    // Index of the inline sprite in the offset table of the sprite atlas plus one, 0 for glyphs and underlines.
+    // The local position and the size in glyph are in texels of the sprite then.
+    sprite: u32,
 }
 
 impl GlyphVertex {
-    const ATTRIBS: [VertexAttribute; 6] = vertex_attr_array![0 => Float32, 1 => Float32, 2 => Float32, 3 => Float32, 4 => Uint32, 5 => Uint32];
+    const ATTRIBS: [VertexAttribute; 13] = vertex_attr_array![0 => Float32, 1 => Float32, 2 => Float32, 3 => Float32, 4 => Uint32, 5 => Uint32, 6 => Float32x4, 7 => Float32x2, 8 => Uint32x4, 9 => Float32x4, 10 => Uint32, 11 => Uint32x3, 12 => Uint32];
     fn desc() -> VertexBufferLayout<'static> {
         use std::mem;
         VertexBufferLayout {
@@ -45,6 +166,310 @@ impl GlyphVertex {
     }
 }
 
+/// The style of a part of a text
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct SpanStyle<F: FontId> {
+    pub font: F,
+    pub size: u16,
+    pub color: [f32; 4],
+    pub underline: bool,
+    pub effect: TextEffect,
+}
+
+/// The style of span index of a text: 0 is the text itself, the spans follow
+pub(crate) fn span_style<S: SpriteId, F: FontId>(text: &Text<S, F>, index: usize) -> SpanStyle<F> {
+    match index.checked_sub(1) {
+        None => SpanStyle {
+            font: text.font,
+            size: text.size,
+            color: text.color,
+            underline: text.underline,
+            effect: text.effect,
+        },
+        Some(index) => {
+            let span = &text.spans[index];
+            SpanStyle {
+                font: span.font,
+                size: span.size,
+                color: span.color,
+                underline: span.underline,
+                effect: span.effect,
+            }
+        }
+    }
+}
+
+/// The inline sprite of span index, see span_style
+pub(crate) fn span_sprite<S: SpriteId, F: FontId>(text: &Text<S, F>, index: usize) -> Option<&S> {
+    text.spans.get(index.checked_sub(1)?)?.sprite.as_ref()
+}
+
+/// The text itself and all of its spans, ordered like span_style. Spans with an inline sprite have no text.
+pub(crate) fn span_texts<S: SpriteId, F: FontId>(text: &Text<S, F>) -> impl Iterator<Item = &str> {
+    std::iter::once(text.text.as_str()).chain(text.spans.iter().map(|span| match span.sprite {
+        Some(_) => "",
+        None => span.text.as_str(),
+    }))
+}
+
+/// What a PlacedGlyph shows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum GlyphKind {
+    Character(char),
+    /// A solid rectangle
+    Underline,
+    /// The inline sprite of the span, scaled to the size of the glyph
+    Sprite,
+}
+
+/// A glyph placed by layout_text, a solid rectangle for an underline or an inline sprite
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct PlacedGlyph {
+    pub x: f32,
+    pub y: f32,
+    pub width: usize,
+    pub height: usize,
+    pub kind: GlyphKind,
+    /// See span_style
+    pub span: usize,
+    /// Index of the glyph in the text, phase of its TextEffect
+    pub index: usize,
+}
+
+/// An inline sprite in the layout. fontdue only lays out characters, so the sprite reserves its space with
+/// non-breaking spaces. The first of them stands for the sprite, the others vanish, and the difference to the
+/// width of the sprite moves the following glyphs of the line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct InlineSprite {
+    width: f32,
+    height: f32,
+    ascent: f32,
+    // Advance of a single placeholder
+    advance: f32,
+    // Index of the first placeholder in the glyphs of the layout
+    first_glyph: usize,
+}
+
+/// Stands in for inline sprites, glues them to the glyphs around them like a word
+const PLACEHOLDER: char = '\u{a0}';
+
+/// Lays out the text of an area with all of its spans as one paragraph and returns its glyphs, underlines first.
+/// sprite_size returns the size of a registered sprite, inline sprites that are not registered take no space.
+/// Shared by the GPU and the software renderer so both place every glyph the same.
+pub(crate) fn layout_text<S: SpriteId, F: FontId>(
+    layout: &mut Layout<usize>,
+    fonts: &HashMap<F, Font>,
+    text: &Text<S, F>,
+    area: &ResolvedArea,
+    sprite_size: impl Fn(&S) -> Option<(f32, f32)>,
+) -> Vec<PlacedGlyph> {
+    let padding = text.padding;
+    layout.reset(&LayoutSettings {
+        x: area.x_min + padding.left,
+        y: area.y_min + padding.top,
+        max_width: Some((area.x_max - area.x_min - padding.left - padding.right).max(0.0)),
+        max_height: Some((area.y_max - area.y_min - padding.top - padding.bottom).max(0.0)),
+        horizontal_align: match text.horizontal_align {
+            HorizontalAlign::Left => fontdue::layout::HorizontalAlign::Left,
+            HorizontalAlign::Center => fontdue::layout::HorizontalAlign::Center,
+            HorizontalAlign::Right => fontdue::layout::HorizontalAlign::Right,
+        },
+        vertical_align: match text.vertical_align {
+            VerticalAlign::Top => fontdue::layout::VerticalAlign::Top,
+            VerticalAlign::Middle => fontdue::layout::VerticalAlign::Middle,
+            VerticalAlign::Bottom => fontdue::layout::VerticalAlign::Bottom,
+        },
+        line_height: text.line_height,
+        wrap_style: match text.wrap {
+            Wrap::Word => WrapStyle::Word,
+            Wrap::Letter => WrapStyle::Letter,
+        },
+        wrap_hard_breaks: true,
+    });
+    // Per span, see span_style
+    let mut inline_sprites: Vec<Option<InlineSprite>> = Vec::new();
+    let mut placeholders = String::new();
+    for (index, span_text) in span_texts(text).enumerate() {
+        let style = span_style(text, index);
+        // Spans in a font that was never added are left out
+        let Some(font) = fonts.get(&style.font) else {
+            inline_sprites.push(None);
+            continue;
+        };
+        let px = style.size as f32;
+        let inline_sprite = span_sprite(text, index)
+            .and_then(&sprite_size)
+            .zip(font.horizontal_line_metrics(px))
+            .map(|((sprite_width, sprite_height), metrics)| {
+                // Rounded up like fontdue rounds the line metrics
+                let ascent = metrics.ascent.ceil();
+                let height = ascent - metrics.descent.ceil();
+                let advance = font.metrics(PLACEHOLDER, px).advance_width.ceil();
+                InlineSprite {
+                    width: (height * sprite_width / sprite_height.max(1.0)).round(),
+                    height,
+                    ascent,
+                    advance,
+                    first_glyph: layout.glyphs().len(),
+                }
+            })
+            .filter(|sprite| sprite.width > 0.0 && sprite.height > 0.0);
+        let span_text = match inline_sprite {
+            Some(sprite) => {
+                let count = match sprite.advance > 0.0 {
+                    true => (sprite.width / sprite.advance).round().max(1.0) as usize,
+                    false => 1,
+                };
+                placeholders.clear();
+                placeholders.extend(std::iter::repeat_n(PLACEHOLDER, count));
+                placeholders.as_str()
+            }
+            None => span_text,
+        };
+        inline_sprites.push(inline_sprite);
+        layout.append(
+            &[font],
+            &TextStyle {
+                text: span_text,
+                px,
+                font_index: 0,
+                user_data: index,
+            },
+        );
+    }
+
+    // fontdue knows no letter spacing, so the glyphs of every line are moved apart afterwards.
+    // The line is moved back by the part of the extra width its alignment puts on the left.
+    let spacing = text.letter_spacing;
+    let align = match text.horizontal_align {
+        HorizontalAlign::Left => 0.0,
+        HorizontalAlign::Center => 0.5,
+        HorizontalAlign::Right => 1.0,
+    };
+    let glyphs = layout.glyphs();
+    // Whether the glyph is spaced like a letter and the width it adds to the line besides its advance
+    let extra_width = |index: usize| match inline_sprites[glyphs[index].user_data] {
+        Some(sprite) if sprite.first_glyph == index => (true, sprite.width - sprite.advance),
+        Some(sprite) => (false, -sprite.advance),
+        None => (true, 0.0),
+    };
+    let mut underlines = Vec::new();
+    let mut placed = Vec::with_capacity(glyphs.len());
+    for line in layout.lines().into_iter().flatten() {
+        let line_glyphs = line.glyph_start..(line.glyph_end + 1).min(glyphs.len());
+        let (letters, width) = line_glyphs
+            .clone()
+            .map(extra_width)
+            .fold((0, 0.0), |(letters, width), (letter, extra)| {
+                (letters + letter as usize, width + extra)
+            });
+        let shift = ((letters.max(1) - 1) as f32 * spacing + width) * align;
+        // (span, x_min, x_max) of the underline that is being built
+        let mut underline: Option<(usize, f32, f32)> = None;
+        let (mut letter, mut offset) = (0, 0.0);
+        for index in line_glyphs {
+            let glyph = &glyphs[index];
+            let (is_letter, extra) = extra_width(index);
+            let x = glyph.x + letter as f32 * spacing + offset - shift;
+            letter += is_letter as usize;
+            offset += extra;
+            let glyph = match inline_sprites[glyph.user_data] {
+                Some(sprite) if sprite.first_glyph == index => PlacedGlyph {
+                    x,
+                    y: (line.baseline_y - sprite.ascent).round(),
+                    width: sprite.width as usize,
+                    height: sprite.height as usize,
+                    kind: GlyphKind::Sprite,
+                    span: glyph.user_data,
+                    index: placed.len(),
+                },
+                Some(_) => continue,
+                None => PlacedGlyph {
+                    x,
+                    y: glyph.y,
+                    width: glyph.width,
+                    height: glyph.height,
+                    kind: GlyphKind::Character(glyph.parent),
+                    span: glyph.user_data,
+                    index: placed.len(),
+                },
+            };
+            placed.push(glyph);
+            underline = match underline {
+                Some((span, x_min, x_max)) if span == glyph.span => {
+                    Some((span, x_min, x_max.max(x + glyph.width as f32)))
+                }
+                _ => {
+                    underlines.extend(underline.map(|run| underline_glyph(text, line, run)));
+                    span_style(text, glyph.span).underline.then_some((
+                        glyph.span,
+                        x,
+                        x + glyph.width as f32,
+                    ))
+                }
+            };
+        }
+        underlines.extend(underline.map(|run| underline_glyph(text, line, run)));
+    }
+    underlines.extend(placed);
+    underlines
+}
+
+/// The underline below a run of glyphs of one span, as thick as the font size allows for pixel fonts
+fn underline_glyph<S: SpriteId, F: FontId>(
+    text: &Text<S, F>,
+    line: &LinePosition,
+    (span, x_min, x_max): (usize, f32, f32),
+) -> PlacedGlyph {
+    let thickness = (span_style(text, span).size as f32 / 16.0).round().max(1.0);
+    PlacedGlyph {
+        x: x_min,
+        y: (line.baseline_y + thickness).round(),
+        width: (x_max - x_min).round().max(0.0) as usize,
+        height: thickness as usize,
+        kind: GlyphKind::Underline,
+        span,
+        index: 0,
+    }
+}
+
+/// Every glyph of a text is drawn once per layer: first the shadow, then the outline and then the glyph itself,
+/// so no outline covers a neighboring glyph
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct TextLayer {
+    /// None for the glyphs themselves, they have the color of their span
+    pub color: Option<[f32; 4]>,
+    pub dilation: u32,
+    pub offset_x: f32,
+    pub offset_y: f32,
+}
+
+/// The layers of a text from bottom to top, shared by the GPU and the software renderer
+pub(crate) fn text_layers<S: SpriteId, F: FontId>(
+    text: &Text<S, F>,
+) -> impl Iterator<Item = TextLayer> {
+    let outline_width = text.outline.map_or(0, |outline| outline.width as u32);
+    let shadow = text.shadow.map(|shadow| TextLayer {
+        color: Some(shadow.color),
+        dilation: outline_width,
+        offset_x: shadow.offset_x,
+        offset_y: shadow.offset_y,
+    });
+    let outline = text.outline.map(|outline| TextLayer {
+        color: Some(outline.color),
+        dilation: outline_width,
+        offset_x: 0.0,
+        offset_y: 0.0,
+    });
+    let glyphs = TextLayer {
+        color: None,
+        dilation: 0,
+        offset_x: 0.0,
+        offset_y: 0.0,
+    };
+    shadow.into_iter().chain(outline).chain(Some(glyphs))
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum RasterizedGlyph {
     Invisible,
@@ -65,17 +490,19 @@ pub struct TextRenderer<F: FontId> {
     fonts: HashMap<F, Font>,
     atlas_allocators: Vec<SimpleAtlasAllocator>,
     atlas_texture: Texture,
+    // Kept alive for the atlas bind group
+    #[allow(dead_code)]
     atlas_texture_view: TextureView,
-    atlas_sampler: Sampler,
+    #[allow(dead_code)]
     atlas_bind_group_layout: BindGroupLayout,
     atlas_bind_group: BindGroup,
     render_pipeline: RenderPipeline,
     // (font_id, font_size, character) -> RasterizedGlyph
     rasterized_glyphs: HashMap<(F, u16, char), RasterizedGlyph>,
 
-    // One vertex buffer per z-layer
-    vertex_buffers: Vec<VertexBuffer>,
-    layout: Layout,
+    // Glyphs of all areas, in draw order
+    vertex_buffer: VertexBuffer,
+    layout: Layout<usize>,
 
     cursor_picking_pipeline: RenderPipeline,
 }
@@ -86,11 +513,12 @@ impl<F: FontId> TextRenderer<F> {
         format: TextureFormat,
         atlas_area: u32,
         uniform_bind_group_layout: &BindGroupLayout,
+        sprite_atlas_bind_group_layout: &BindGroupLayout,
         fonts: HashMap<F, Font>,
     ) -> Self {
         let max_texture_size = device.limits().max_texture_dimension_2d.min(16384);
         let max_texture_area = max_texture_size * max_texture_size;
-        let atlas_count = (atlas_area + max_texture_area - 1) / max_texture_area;
+        let atlas_count = atlas_area.div_ceil(max_texture_area);
 
         let atlas_allocators = (0..atlas_count)
             .map(|_| {
@@ -103,7 +531,8 @@ impl<F: FontId> TextRenderer<F> {
             size: Extent3d {
                 width: max_texture_size,
                 height: max_texture_size,
-                depth_or_array_layers: atlas_count,
+                // The GL backend treats single layer textures as plain 2d textures, which breaks the array view
+                depth_or_array_layers: atlas_count.max(2),
             },
             mip_level_count: 1,
             sample_count: 1,
@@ -119,51 +548,28 @@ impl<F: FontId> TextRenderer<F> {
             aspect: TextureAspect::All,
             ..Default::default()
         });
-        let atlas_sampler = device.create_sampler(&SamplerDescriptor {
-            label: Some("STGI Glyph Atlas Sampler"),
-            address_mode_u: AddressMode::ClampToEdge,
-            address_mode_v: AddressMode::ClampToEdge,
-            address_mode_w: AddressMode::ClampToEdge,
-            mag_filter: FilterMode::Nearest,
-            min_filter: FilterMode::Nearest,
-            mipmap_filter: FilterMode::Nearest,
-            ..Default::default()
-        });
         let atlas_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: Some("STGI Glyph Atlas Bind Group Layout"),
-            entries: &[
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Texture {
-                        multisampled: false,
-                        view_dimension: TextureViewDimension::D2Array,
-                        sample_type: TextureSampleType::Float { filterable: true },
-                    },
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
-                    count: None,
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: TextureViewDimension::D2Array,
+                    // Glyphs are read texel by texel, outlines need the neighbors of a texel
+                    sample_type: TextureSampleType::Float { filterable: false },
                 },
-            ],
+                count: None,
+            }],
         });
 
         let atlas_bind_group = device.create_bind_group(&BindGroupDescriptor {
             label: Some("STGI Glyph Atlas Bind Group"),
             layout: &atlas_bind_group_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: BindingResource::TextureView(&atlas_texture_view),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::Sampler(&atlas_sampler),
-                },
-            ],
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(&atlas_texture_view),
+            }],
         });
 
         let render_shader = device.create_shader_module(ShaderModuleDescriptor {
@@ -172,7 +578,11 @@ impl<F: FontId> TextRenderer<F> {
         });
         let render_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
             label: Some("STGI Text Pipeline Layout"),
-            bind_group_layouts: &[&atlas_bind_group_layout, &uniform_bind_group_layout],
+            bind_group_layouts: &[
+                &atlas_bind_group_layout,
+                uniform_bind_group_layout,
+                sprite_atlas_bind_group_layout,
+            ],
             push_constant_ranges: &[],
         });
         let render_pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
@@ -222,7 +632,11 @@ impl<F: FontId> TextRenderer<F> {
         let cursor_picking_pipeline_layout =
             device.create_pipeline_layout(&PipelineLayoutDescriptor {
                 label: Some("STGI Cursor Picking Text Pipeline Layout"),
-                bind_group_layouts: &[&atlas_bind_group_layout, &uniform_bind_group_layout],
+                bind_group_layouts: &[
+                    &atlas_bind_group_layout,
+                    uniform_bind_group_layout,
+                    sprite_atlas_bind_group_layout,
+                ],
                 push_constant_ranges: &[],
             });
         let cursor_picking_pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
@@ -253,7 +667,7 @@ impl<F: FontId> TextRenderer<F> {
                 module: &cursor_picking_shader,
                 entry_point: "fs_main",
                 targets: &[Some(ColorTargetState {
-                    format: TextureFormat::R32Uint,
+                    format: TextureFormat::Rgba32Uint,
                     blend: None,
                     write_mask: ColorWrites::ALL,
                 })],
@@ -263,31 +677,28 @@ impl<F: FontId> TextRenderer<F> {
             cache: None,
         });
 
-        let vertex_buffers: Vec<VertexBuffer> = (0..4)
-            .map(|_| VertexBuffer {
-                staging: Vec::new(),
-                buffer: device.create_buffer(&BufferDescriptor {
-                    label: Some("STGI Text Vertex Buffer"),
-                    size: 256 * 4 * std::mem::size_of::<GlyphVertex>() as u64,
-                    usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
-                    mapped_at_creation: false,
-                }),
-                len: 0,
-                capacity: 256,
-            })
-            .collect();
+        let vertex_buffer = VertexBuffer {
+            staging: Vec::new(),
+            buffer: device.create_buffer(&BufferDescriptor {
+                label: Some("STGI Text Vertex Buffer"),
+                size: 256 * 4 * std::mem::size_of::<GlyphVertex>() as u64,
+                usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            len: 0,
+            capacity: 256,
+        };
 
         Self {
             fonts,
             atlas_allocators,
             atlas_texture,
             atlas_texture_view,
-            atlas_sampler,
             atlas_bind_group_layout,
             atlas_bind_group,
             render_pipeline,
             rasterized_glyphs: HashMap::default(),
-            vertex_buffers,
+            vertex_buffer,
             layout: Layout::new(CoordinateSystem::PositiveYDown),
             cursor_picking_pipeline,
         }
@@ -301,7 +712,9 @@ impl<F: FontId> TextRenderer<F> {
         {
             return;
         }
-        let font = self.fonts.get(&font_id).unwrap();
+        let Some(font) = self.fonts.get(&font_id) else {
+            return;
+        };
         let (metrics, bitmap) = font.rasterize(c, font_size as f32);
 
         if metrics.width == 0 || metrics.height == 0 {
@@ -361,142 +774,227 @@ impl<F: FontId> TextRenderer<F> {
         }
     }
 
-    /// Recreates the vertex buffers.
-    pub fn update<'a, S: SpriteId>(
+    /// Recreates the vertex buffer. The areas must be given in draw order.
+    /// Returns the glyph range of every area that has visible text, in the same order.
+    pub(crate) fn update<'a, S>(
         &mut self,
         device: &Device,
         queue: &Queue,
-        ui_areas: impl Iterator<Item = (&'a UiAreaHandle, &'a UiArea<S, F>)>,
-    ) where
+        ui_areas: impl Iterator<Item = (UiAreaHandle, &'a InternalUiArea<S, F>)>,
+        sprites: &SpriteAtlas<S>,
+    ) -> Vec<(UiAreaHandle, Range<u32>)>
+    where
         F: 'a,
-        S: 'a,
+        S: SpriteId + 'a,
     {
-        self.vertex_buffers.iter_mut().for_each(|buffer| {
-            buffer.len = 0;
-            buffer.staging.clear();
+        let mut ranges = Vec::new();
+        self.vertex_buffer.len = 0;
+        self.vertex_buffer.staging.clear();
+        for (area_id, area) in ui_areas {
+            let glyphs = self.push_glyphs(queue, area_id, area, sprites);
+            if !glyphs.is_empty() {
+                ranges.push((area_id, glyphs));
+            }
+        }
+        let buffer = &mut self.vertex_buffer;
+        if !buffer.staging.is_empty() {
+            if buffer.capacity < buffer.staging.len() as u32 {
+                buffer.capacity = (buffer.capacity * 2).max(buffer.staging.len() as u32);
+                buffer.buffer = device.create_buffer(&BufferDescriptor {
+                    label: Some("STGI Text Vertex Buffer"),
+                    size: buffer.capacity as u64 * 4 * std::mem::size_of::<GlyphVertex>() as u64,
+                    usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+            }
+            queue.write_buffer(&buffer.buffer, 0, bytemuck::cast_slice(&buffer.staging));
+        }
+        buffer.len = buffer.staging.len() as u32;
+        ranges
+    }
+
+    /// Lays out the text of a single area again and overwrites its glyphs in place, e.g. after its sprite changed.
+    /// glyphs is the range the area got from the last update, None if it had no visible text.
+    /// Returns false and changes nothing if the amount of glyphs changed, update has to be called then.
+    pub(crate) fn update_area<S: SpriteId>(
+        &mut self,
+        queue: &Queue,
+        area_id: UiAreaHandle,
+        area: &InternalUiArea<S, F>,
+        glyphs: Option<Range<u32>>,
+        sprites: &SpriteAtlas<S>,
+    ) -> bool {
+        let glyphs = glyphs.unwrap_or(0..0);
+        let new_glyphs = self.push_glyphs(queue, area_id, area, sprites);
+        let buffer = &mut self.vertex_buffer;
+        let fits = new_glyphs.len() == glyphs.len();
+        if fits && !glyphs.is_empty() {
+            let (start, end) = (new_glyphs.start as usize, new_glyphs.end as usize);
+            buffer
+                .staging
+                .copy_within(start..end, glyphs.start as usize);
+            queue.write_buffer(
+                &buffer.buffer,
+                glyphs.start as u64 * 4 * std::mem::size_of::<GlyphVertex>() as u64,
+                bytemuck::cast_slice(&buffer.staging[glyphs.start as usize..glyphs.end as usize]),
+            );
+        }
+        buffer.staging.truncate(new_glyphs.start as usize);
+        fits
+    }
+
+    /// Lays out the text of an area and appends its glyphs to the staging buffer, once per TextLayer.
+    /// Returns the range of the appended glyphs, which is empty for disabled areas and areas without text.
+    fn push_glyphs<S: SpriteId>(
+        &mut self,
+        queue: &Queue,
+        area_id: UiAreaHandle,
+        area: &InternalUiArea<S, F>,
+        sprites: &SpriteAtlas<S>,
+    ) -> Range<u32> {
+        let first_glyph = self.vertex_buffer.staging.len() as u32;
+        let Some(text) = area.area.text.as_ref().filter(|_| area.resolved.enabled) else {
+            return first_glyph..first_glyph;
+        };
+        let area = &area.resolved;
+        for (index, span_text) in span_texts(text).enumerate() {
+            let style = span_style(text, index);
+            self.rasterize_glyphs(queue, span_text, style.font, style.size);
+        }
+        let buffer = &mut self.vertex_buffer;
+        let clip = ClipRect::to_instance_data(area.clip);
+        let origin = [area.x_min, area.y_min];
+        let glyphs = layout_text(&mut self.layout, &self.fonts, text, area, |sprite| {
+            let info = sprites.info(sprite)?;
+            Some((info.width, info.height))
         });
-        for (area_id, area) in ui_areas.filter(|(_, area)| area.enabled) {
-            if let Some(text) = &area.text {
-                self.rasterize_glyphs(queue, &text.text, text.font, text.size);
-                let buffer = &mut self.vertex_buffers[area.z.to_usize()];
-                let font = self.fonts.get(&text.font).unwrap();
-                let layout_settings = LayoutSettings {
-                    x: area.x_min,
-                    y: area.y_min,
-                    max_width: Some(area.x_max - area.x_min),
-                    max_height: Some(area.y_max - area.y_min),
-                    horizontal_align: HorizontalAlign::Center,
-                    vertical_align: VerticalAlign::Middle,
-                    line_height: 1.0,
-                    wrap_style: WrapStyle::Word,
-                    wrap_hard_breaks: true,
-                };
-                self.layout.reset(&layout_settings);
-                self.layout.append(
-                    &[font],
-                    &TextStyle {
-                        text: &text.text,
-                        px: text.size as f32,
-                        font_index: 0,
-                        user_data: (),
-                    },
-                );
-                for glyph in self.layout.glyphs() {
-                    if let RasterizedGlyph::Visible {
-                        atlas_index,
-                        allocation,
-                    } = self
+        for layer in text_layers(text) {
+            for glyph in &glyphs {
+                if glyph.width == 0 || glyph.height == 0 {
+                    continue;
+                }
+                let style = span_style(text, glyph.span);
+                // Underlines are solid, which is marked by an atlas x of u32::MAX.
+                // The shader looks up the current frame of inline sprites.
+                let (atlas_index, glyph_rect, sprite) = match glyph.kind {
+                    GlyphKind::Underline => {
+                        (0, [u32::MAX, 0, glyph.width as u32, glyph.height as u32], 0)
+                    }
+                    GlyphKind::Character(character) => match self
                         .rasterized_glyphs
-                        .get(&(text.font, text.size, glyph.parent))
-                        .unwrap()
+                        .get(&(style.font, style.size, character))
                     {
-                        let atlas_size =
-                            self.atlas_allocators[*atlas_index as usize].size().width as f32;
-                        buffer.staging.push([
-                            GlyphVertex {
-                                pos_x: glyph.x,
-                                pos_y: glyph.y,
-                                tex_x: (allocation.min.x + 1) as f32 / atlas_size,
-                                tex_y: (allocation.min.y + 1) as f32 / atlas_size,
-                                atlas_index: *atlas_index,
-                                area_id: area_id.id.get(),
-                            },
-                            GlyphVertex {
-                                pos_x: glyph.x + glyph.width as f32,
-                                pos_y: glyph.y,
-                                tex_x: (allocation.max.x - 1) as f32 / atlas_size,
-                                tex_y: (allocation.min.y + 1) as f32 / atlas_size,
-                                atlas_index: *atlas_index,
-                                area_id: area_id.id.get(),
-                            },
-                            GlyphVertex {
-                                pos_x: glyph.x + glyph.width as f32,
-                                pos_y: glyph.y + glyph.height as f32,
-                                tex_x: (allocation.max.x - 1) as f32 / atlas_size,
-                                tex_y: (allocation.max.y - 1) as f32 / atlas_size,
-                                atlas_index: *atlas_index,
-                                area_id: area_id.id.get(),
-                            },
-                            GlyphVertex {
-                                pos_x: glyph.x,
-                                pos_y: glyph.y + glyph.height as f32,
-                                tex_x: (allocation.min.x + 1) as f32 / atlas_size,
-                                tex_y: (allocation.max.y - 1) as f32 / atlas_size,
-                                atlas_index: *atlas_index,
-                                area_id: area_id.id.get(),
-                            },
-                        ]);
+                        Some(RasterizedGlyph::Visible {
+                            atlas_index,
+                            allocation,
+                        }) => (
+                            *atlas_index,
+                            [
+                                allocation.min.x as u32 + 1,
+                                allocation.min.y as u32 + 1,
+                                glyph.width as u32,
+                                glyph.height as u32,
+                            ],
+                            0,
+                        ),
+                        _ => continue,
+                    },
+                    // Inline sprites have no outline or shadow
+                    GlyphKind::Sprite if layer.color.is_some() => continue,
+                    GlyphKind::Sprite => {
+                        let Some((index, info)) =
+                            span_sprite(text, glyph.span).and_then(|sprite| {
+                                Some((sprites.index(sprite)?, sprites.info(sprite)?))
+                            })
+                        else {
+                            continue;
+                        };
+                        (0, [0, 0, info.width as u32, info.height as u32], index + 1)
                     }
-                }
-            }
-        }
-        for buffer in &mut self.vertex_buffers {
-            if buffer.staging.is_empty() {
-                buffer.len = 0;
-            } else {
-                if buffer.capacity < buffer.staging.len() as u32 {
-                    buffer.capacity *= 2;
-                    buffer.buffer = device.create_buffer(&BufferDescriptor {
-                        label: Some("STGI Text Vertex Buffer"),
-                        size: buffer.capacity as u64 * std::mem::size_of::<GlyphVertex>() as u64,
-                        usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
-                        mapped_at_creation: false,
-                    });
-                }
-                queue.write_buffer(&buffer.buffer, 0, bytemuck::cast_slice(&buffer.staging));
-                buffer.len = buffer.staging.len() as u32;
+                };
+                let dilation = layer.dilation as f32;
+                let x_min = glyph.x + layer.offset_x - dilation;
+                let y_min = glyph.y + layer.offset_y - dilation;
+                let x_max = glyph.x + layer.offset_x + glyph.width as f32 + dilation;
+                let y_max = glyph.y + layer.offset_y + glyph.height as f32 + dilation;
+                let local_max_x = glyph_rect[2] as f32 + dilation;
+                let local_max_y = glyph_rect[3] as f32 + dilation;
+                // The opacity of the area and its parents fades every layer
+                let [r, g, b, a] = layer.color.unwrap_or(style.color);
+                let color = [r, g, b, a * area.opacity];
+                let vertex = |pos_x, pos_y, local_x, local_y| GlyphVertex {
+                    pos_x,
+                    pos_y,
+                    local_x,
+                    local_y,
+                    atlas_index,
+                    area_id: area_id.id.get(),
+                    clip,
+                    origin,
+                    glyph: glyph_rect,
+                    color,
+                    dilation: layer.dilation,
+                    effect: match glyph.kind {
+                        GlyphKind::Underline => [0; 3],
+                        _ => style.effect.to_vertex_data(glyph.index, style.size),
+                    },
+                    sprite,
+                };
+                buffer.staging.push([
+                    vertex(x_min, y_min, -dilation, -dilation),
+                    vertex(x_max, y_min, local_max_x, -dilation),
+                    vertex(x_max, y_max, local_max_x, local_max_y),
+                    vertex(x_min, y_max, -dilation, local_max_y),
+                ]);
             }
         }
+        first_glyph..buffer.staging.len() as u32
     }
 
     pub fn amount_indices_needed(&self) -> usize {
-        self.vertex_buffers
-            .iter()
-            .map(|b| b.len as usize)
-            .max()
-            .unwrap()
-            * 6
+        self.vertex_buffer.len as usize * 6
     }
 
-    pub fn render(&mut self, render_pass: &mut RenderPass, z: usize) {
-        let buffer = &self.vertex_buffers[z];
-        if buffer.len == 0 {
-            return;
-        }
+    /// sprite_atlas is the bind group of the sprite atlas, for inline sprites
+    pub fn render(
+        &self,
+        render_pass: &mut RenderPass,
+        glyphs: Range<u32>,
+        sprite_atlas: &BindGroup,
+    ) {
         render_pass.set_pipeline(&self.render_pipeline);
-        render_pass.set_bind_group(0, &self.atlas_bind_group, &[]);
-        render_pass.set_vertex_buffer(0, buffer.buffer.slice(..));
-        render_pass.draw_indexed(0..buffer.staging.len() as u32 * 6, 0, 0..1);
+        self.draw_glyphs(render_pass, glyphs, sprite_atlas);
     }
 
-    pub fn render_cursor_picking(&mut self, render_pass: &mut RenderPass, z: usize) {
-        let buffer = &self.vertex_buffers[z];
-        if buffer.len == 0 {
+    pub fn render_cursor_picking(
+        &self,
+        render_pass: &mut RenderPass,
+        glyphs: Range<u32>,
+        sprite_atlas: &BindGroup,
+    ) {
+        render_pass.set_pipeline(&self.cursor_picking_pipeline);
+        self.draw_glyphs(render_pass, glyphs, sprite_atlas);
+    }
+
+    fn draw_glyphs(
+        &self,
+        render_pass: &mut RenderPass,
+        glyphs: Range<u32>,
+        sprite_atlas: &BindGroup,
+    ) {
+        if glyphs.is_empty() {
             return;
         }
-        render_pass.set_pipeline(&self.cursor_picking_pipeline);
+        // Every glyph is a quad of 4 vertices, the index buffer always starts at the first one
+        let glyph_size = 4 * std::mem::size_of::<GlyphVertex>() as u64;
         render_pass.set_bind_group(0, &self.atlas_bind_group, &[]);
-        render_pass.set_vertex_buffer(0, buffer.buffer.slice(..));
-        render_pass.draw_indexed(0..buffer.staging.len() as u32 * 6, 0, 0..1);
+        render_pass.set_bind_group(2, sprite_atlas, &[]);
+        render_pass.set_vertex_buffer(
+            0,
+            self.vertex_buffer
+                .buffer
+                .slice(glyphs.start as u64 * glyph_size..glyphs.end as u64 * glyph_size),
+        );
+        render_pass.draw_indexed(0..glyphs.len() as u32 * 6, 0, 0..1);
     }
 }